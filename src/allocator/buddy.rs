@@ -0,0 +1,601 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{fast_align_up, AllocatorStats, HeapAllocator, HeapStats, Locked, UsageCounters};
+
+/// Order of the smallest block, big enough to hold a `FreeBlock`.
+const MIN_ORDER: usize = 4;
+const MIN_BLOCK_SIZE: usize = 1 << MIN_ORDER;
+const ORDERS: usize = usize::BITS as usize;
+
+const FRAME_ORDER: usize = 12;
+
+/// Stored at the start of each free block.
+struct FreeBlock {
+    previous: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A binary buddy allocator: blocks of 2^order bytes are split in two
+/// buddies to serve smaller allocations and merged back with their buddy
+/// when both are free.
+///
+/// For each pair of buddies one bit, kept in a bitmap carved from the start of
+/// the heap, tells whether exactly one of them is free. The bitmap takes
+/// about 1/128 of the heap. When the heap grows past the size the bitmap
+/// covers, a bitmap twice as big is carved from the start of the extension.
+pub struct BuddyAllocator {
+    base: usize,
+    end: usize,
+    /// The size of the heap covered by the bitmap.
+    capacity: usize,
+    max_order: usize,
+    free_lists: [Option<NonNull<FreeBlock>>; ORDERS],
+    bitmap: usize,
+    bitmap_size: usize,
+    /// Index of the first bit of each order in the bitmap.
+    bitmap_offsets: [usize; ORDERS],
+    counters: UsageCounters,
+}
+
+// SAFETY: the free blocks are only reached through the allocator.
+unsafe impl Send for BuddyAllocator {}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuddyAllocator {
+    #[must_use]
+    pub const fn new() -> Self {
+        const _: () = assert!(mem::size_of::<FreeBlock>() <= MIN_BLOCK_SIZE);
+        Self {
+            base: 0,
+            end: 0,
+            capacity: 0,
+            max_order: MIN_ORDER,
+            free_lists: [None; ORDERS],
+            bitmap: 0,
+            bitmap_size: 0,
+            bitmap_offsets: [0; ORDERS],
+            counters: UsageCounters::new(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.base = fast_align_up(heap_start, MIN_BLOCK_SIZE);
+        self.end = heap_start + heap_size;
+        if self.end <= self.base + MIN_BLOCK_SIZE {
+            return;
+        }
+
+        unsafe {
+            // SAFETY: the heap is unused and bigger than the bitmap.
+            self.move_bitmap(self.base, self.end - self.base);
+            self.free_range(self.base + self.bitmap_size, self.end);
+        }
+    }
+
+    /// Gives the `additional` bytes following the heap to the allocator.
+    ///
+    /// The extension is left unused if the heap outgrows its bitmap and the
+    /// extension is too small to hold the new one, `KernelHeap` grows the
+    /// heap by at least 1/8 of its size so that it does not happen.
+    ///
+    /// # Safety
+    ///
+    /// The memory following the heap must be valid and unused.
+    pub unsafe fn extend(&mut self, additional: usize) {
+        if self.base == 0 {
+            return;
+        }
+        let start = fast_align_up(self.end, MIN_BLOCK_SIZE);
+        let end = self.end + additional;
+        if end - self.base <= self.capacity {
+            self.end = end;
+            unsafe {
+                // SAFETY: the caller guarantees that the extension is unused.
+                self.free_range(start, end);
+            }
+            return;
+        }
+
+        let capacity = (2 * self.capacity).max(end - self.base);
+        if start + bitmap_layout(capacity).1 > end {
+            return;
+        }
+        let (old_bitmap, old_bitmap_size) = (self.bitmap, self.bitmap_size);
+        self.end = end;
+        unsafe {
+            // SAFETY: the caller guarantees that the extension is unused, the
+            // old bitmap is not used anymore once the new one is built.
+            self.move_bitmap(start, capacity);
+            self.free_range(start + self.bitmap_size, end);
+            self.free_range(old_bitmap, old_bitmap + old_bitmap_size);
+        }
+    }
+
+    /// Builds a bitmap covering `capacity` bytes of heap at `addr` from the
+    /// free lists.
+    ///
+    /// # Safety
+    ///
+    /// The bitmap must fit in unused memory at `addr`.
+    unsafe fn move_bitmap(&mut self, addr: usize, capacity: usize) {
+        self.capacity = capacity;
+        self.max_order = capacity.ilog2() as usize;
+        (self.bitmap_offsets, self.bitmap_size) = bitmap_layout(capacity);
+        self.bitmap = addr;
+        unsafe {
+            // SAFETY: the caller guarantees that the memory is unused.
+            ptr::write_bytes(self.bitmap as *mut u8, 0, self.bitmap_size);
+        }
+
+        // A free block is the only free one of its pair, its buddy is either
+        // used or merged with it.
+        for order in MIN_ORDER..self.max_order {
+            let mut current = self.free_lists[order];
+            while let Some(block) = current {
+                self.toggle(block.as_ptr() as usize, order);
+                current = unsafe {
+                    // SAFETY: the blocks of the free lists are valid.
+                    block.as_ref().next
+                };
+            }
+        }
+    }
+
+    /// Cuts `start..end` in the biggest aligned blocks and frees them.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused and in the part of the heap covered by the
+    /// bitmap.
+    unsafe fn free_range(&mut self, start: usize, end: usize) {
+        let mut addr = fast_align_up(start, MIN_BLOCK_SIZE);
+        while addr + MIN_BLOCK_SIZE <= end {
+            let alignment_order = (addr - self.base).trailing_zeros() as usize;
+            let fitting_order = (end - addr).ilog2() as usize;
+            let order = alignment_order.min(fitting_order).min(self.max_order);
+            unsafe {
+                // SAFETY: the caller guarantees that the block is unused.
+                self.free_block(addr, order);
+            }
+            addr += 1 << order;
+        }
+    }
+
+    /// The order of the blocks serving `layout`, `None` if it cannot be served.
+    fn order_for(&self, layout: Layout) -> Option<usize> {
+        // The blocks are aligned on their size relatively to the base, there is
+        // no block while the base is 0, before `init`.
+        let max_align = 1_usize.checked_shl(self.base.trailing_zeros())?;
+        if layout.align() > max_align {
+            return None;
+        }
+
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(MIN_BLOCK_SIZE)
+            .checked_next_power_of_two()?;
+        let order = size.trailing_zeros() as usize;
+        (order <= self.max_order).then_some(order)
+    }
+
+    /// Takes a block of the given order, splitting a bigger one if needed.
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
+        let mut current_order = (order..=self.max_order).find(|&o| self.free_lists[o].is_some())?;
+        let block = self.pop(current_order)?;
+        self.toggle(block, current_order);
+
+        while current_order > order {
+            current_order -= 1;
+            let upper_half = block + (1 << current_order);
+            self.toggle(upper_half, current_order);
+            unsafe {
+                // SAFETY: the upper half of the block is unused.
+                self.push(upper_half, current_order);
+            }
+        }
+
+        Some(block)
+    }
+
+    /// Gives back a block, merging it with its buddy as long as it is free.
+    ///
+    /// # Safety
+    ///
+    /// The block must be unused and come from `alloc_block` with the same order.
+    unsafe fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        while order < self.max_order {
+            let buddy = self.base + ((addr - self.base) ^ (1 << order));
+            if self.toggle(addr, order) {
+                break;
+            }
+            // The buddy is free too, the pair becomes a block of the next order.
+            unsafe {
+                // SAFETY: the bit was set, the buddy is in the free list.
+                self.remove(buddy, order);
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        unsafe {
+            // SAFETY: the caller guarantees that the block is unused.
+            self.push(addr, order);
+        }
+    }
+
+    /// Flips the bit of the pair of buddies containing `addr` and returns it,
+    /// it is set if exactly one of them is free.
+    fn toggle(&mut self, addr: usize, order: usize) -> bool {
+        let bit = self.bitmap_offsets[order] + ((addr - self.base) >> (order + 1));
+        let byte = (self.bitmap + bit / 8) as *mut u8;
+        unsafe {
+            // SAFETY: the bitmap has a bit for each pair of the heap.
+            *byte ^= 1 << (bit % 8);
+            *byte & (1 << (bit % 8)) != 0
+        }
+    }
+
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let next = self.free_lists[order];
+        unsafe {
+            // SAFETY: the caller guarantees that the block is unused.
+            block.write(FreeBlock {
+                previous: None,
+                next,
+            });
+            if let Some(mut next) = next {
+                next.as_mut().previous = NonNull::new(block);
+            }
+        }
+        self.free_lists[order] = NonNull::new(block);
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order]?;
+        unsafe {
+            // SAFETY: the block is in the free list.
+            self.remove(block.as_ptr() as usize, order);
+        }
+        Some(block.as_ptr() as usize)
+    }
+
+    /// Returns the total size of the free blocks and the size of the biggest.
+    fn free_blocks(&self) -> (usize, usize) {
+        let mut free = 0;
+        let mut largest = 0;
+        for (order, &head) in self.free_lists.iter().enumerate() {
+            let mut current = head;
+            while let Some(block) = current {
+                free += 1 << order;
+                largest = 1 << order;
+                current = unsafe {
+                    // SAFETY: the blocks of the free lists are valid.
+                    block.as_ref().next
+                };
+            }
+        }
+        (free, largest)
+    }
+
+    unsafe fn remove(&mut self, addr: usize, order: usize) {
+        let block = unsafe {
+            // SAFETY: the caller guarantees that the block is in the free list.
+            &mut *(addr as *mut FreeBlock)
+        };
+        match block.previous {
+            Some(mut previous) => unsafe { previous.as_mut().next = block.next },
+            None => self.free_lists[order] = block.next,
+        }
+        if let Some(mut next) = block.next {
+            unsafe { next.as_mut().previous = block.previous };
+        }
+    }
+}
+
+/// The index of the first bit of each order and the size of the bitmap
+/// covering `capacity` bytes of heap.
+fn bitmap_layout(capacity: usize) -> ([usize; ORDERS], usize) {
+    let mut offsets = [0; ORDERS];
+    let mut bits = 0;
+    let max_order = capacity.ilog2() as usize;
+    for (order, offset) in offsets
+        .iter_mut()
+        .enumerate()
+        .take(max_order + 1)
+        .skip(MIN_ORDER)
+    {
+        *offset = bits;
+        bits += (capacity >> (order + 1)) + 1;
+    }
+    (offsets, fast_align_up(bits.div_ceil(8), MIN_BLOCK_SIZE))
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let Some(block) = allocator
+            .order_for(layout)
+            .and_then(|order| allocator.alloc_block(order))
+        else {
+            return ptr::null_mut();
+        };
+        allocator.counters.allocated(layout);
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        if let Some(order) = allocator.order_for(layout) {
+            allocator.counters.freed(layout);
+            unsafe {
+                // SAFETY: the block was allocated with the same layout.
+                allocator.free_block(ptr as usize, order);
+            }
+        }
+    }
+}
+
+impl HeapAllocator for Locked<BuddyAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.lock().extend(additional);
+        }
+    }
+}
+
+impl AllocatorStats for Locked<BuddyAllocator> {
+    fn heap_stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        let (free, largest_free_block) = allocator.free_blocks();
+        allocator.counters.stats(
+            allocator.end - allocator.base,
+            free,
+            Some(largest_free_block),
+        )
+    }
+
+    fn dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let allocator = self.lock();
+        for order in MIN_ORDER..=allocator.max_order {
+            let mut count = 0;
+            let mut current = allocator.free_lists[order];
+            while let Some(block) = current {
+                count += 1;
+                current = unsafe {
+                    // SAFETY: the blocks of the free lists are valid.
+                    block.as_ref().next
+                };
+            }
+            if count > 0 {
+                writeln!(out, "  order {order} ({} bytes): {count} free", 1 << order)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hands out runs of 2^n contiguous physical frames, for DMA buffers.
+///
+/// The runs are aligned on their size relatively to the start of the managed
+/// frames, the bitmap takes the start of the first frame.
+pub struct BuddyFrameAllocator {
+    buddy: BuddyAllocator,
+    physical_memory_offset: VirtAddr,
+}
+
+impl Default for BuddyFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuddyFrameAllocator {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            buddy: BuddyAllocator::new(),
+            physical_memory_offset: VirtAddr::zero(),
+        }
+    }
+
+    /// Initializes the allocator with the frames it hands out, reached through
+    /// the mapping of the complete physical memory at `physical_memory_offset`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be unused and mapped at `physical_memory_offset`.
+    /// This method must be called only once.
+    pub unsafe fn init(&mut self, frames: PhysFrameRange, physical_memory_offset: VirtAddr) {
+        self.physical_memory_offset = physical_memory_offset;
+        let start = self.to_virt(frames.start);
+        unsafe {
+            self.buddy.init(start, self.to_virt(frames.end) - start);
+        }
+    }
+
+    /// Returns 2^`order` contiguous frames.
+    pub fn allocate_frames(&mut self, order: usize) -> Option<PhysFrameRange> {
+        let block = self.buddy.alloc_block(order + FRAME_ORDER)?;
+        let start = PhysFrame::containing_address(self.to_phys(block));
+        Some(PhysFrame::range(start, start + (1 << order)))
+    }
+
+    /// Gives back frames from `allocate_frames`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be unused and come from `allocate_frames`.
+    pub unsafe fn deallocate_frames(&mut self, frames: PhysFrameRange) {
+        let count = frames.end - frames.start;
+        let order = count.trailing_zeros() as usize;
+        let block = self.to_virt(frames.start);
+        unsafe {
+            self.buddy.free_block(block, order + FRAME_ORDER);
+        }
+    }
+
+    fn to_virt(&self, frame: PhysFrame) -> usize {
+        let addr = self.physical_memory_offset + frame.start_address().as_u64();
+        addr.as_ptr::<u8>() as usize
+    }
+
+    fn to_phys(&self, addr: usize) -> PhysAddr {
+        PhysAddr::new(addr as u64 - self.physical_memory_offset.as_u64())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_frames(0).map(|frames| frames.start)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe {
+            self.deallocate_frames(PhysFrame::range(frame, frame + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod buddy_tests {
+    use super::*;
+    use crate::test_utils::Arena;
+    use crate::{assert, assert_eq};
+
+    static ARENA: Arena<{ 64 * 1024 }> = Arena::new();
+
+    /// Frames of the arena, seen as physical memory mapped at offset 0.
+    fn arena_frames() -> PhysFrameRange {
+        let start = PhysFrame::containing_address(PhysAddr::new(ARENA.start() as u64));
+        let end = PhysFrame::containing_address(PhysAddr::new(ARENA.end() as u64));
+        PhysFrame::range(start, end)
+    }
+
+    #[test_case]
+    fn test_split_and_merge() {
+        let allocator = Locked::new(BuddyAllocator::new());
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size());
+        }
+        let page = Layout::from_size_align(4096, 8).unwrap();
+
+        // The first page holds the bitmap and smaller blocks.
+        let pages: [_; 15] = core::array::from_fn(|_| unsafe { allocator.alloc(page) });
+        assert!(pages.iter().all(|ptr| !ptr.is_null()));
+        assert!(unsafe { allocator.alloc(page) }.is_null());
+
+        for ptr in pages {
+            unsafe { allocator.dealloc(ptr, page) };
+        }
+
+        // The pages were merged back into the biggest blocks.
+        for size in [ARENA.size() / 2, ARENA.size() / 4] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert_eq!(ptr as usize, ARENA.start() + size);
+        }
+    }
+
+    #[test_case]
+    fn test_frame_runs() {
+        let mut allocator = BuddyFrameAllocator::new();
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(arena_frames(), VirtAddr::zero());
+        }
+
+        let run = allocator.allocate_frames(2).unwrap();
+        assert_eq!(run.end - run.start, 4);
+        let offset = run.start.start_address().as_u64() - ARENA.start() as u64;
+        assert_eq!(offset % (4 * 4096), 0);
+        assert!(arena_frames().start <= run.start && run.end <= arena_frames().end);
+
+        // The first frame holds the bitmap, 15 frames are left.
+        let frames: [_; 11] = core::array::from_fn(|_| allocator.allocate_frame());
+        assert!(frames.iter().all(Option::is_some));
+        assert!(allocator.allocate_frames(2).is_none());
+
+        unsafe {
+            for frame in frames.into_iter().flatten() {
+                allocator.deallocate_frame(frame);
+            }
+            allocator.deallocate_frames(run);
+        }
+        let run = allocator.allocate_frames(3);
+        assert!(run.is_some());
+    }
+
+    #[test_case]
+    fn test_extend() {
+        let allocator = Locked::new(BuddyAllocator::new());
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size() / 8);
+        }
+        let quarter = Layout::from_size_align(ARENA.size() / 4, 8).unwrap();
+        assert!(unsafe { allocator.alloc(quarter) }.is_null());
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let kept = unsafe { allocator.alloc(small) };
+        unsafe { kept.write_bytes(0xAB, small.size()) };
+
+        // The heap outgrows the bitmap, a bigger one is carved from the
+        // extension, then the next extension fits in it.
+        let step = ARENA.size() / 16;
+        unsafe { allocator.extend(step) };
+        assert_eq!(allocator.heap_stats().heap_size, 3 * step);
+        let free = allocator.heap_stats().free;
+        unsafe { allocator.extend(step) };
+        assert_eq!(allocator.heap_stats().free, free + step);
+
+        unsafe { allocator.extend(ARENA.size() - 4 * step) };
+        assert_eq!(allocator.heap_stats().heap_size, ARENA.size());
+        let ptr = unsafe { allocator.alloc(quarter) };
+        assert!(ARENA.start() <= ptr as usize && ptr as usize + quarter.size() <= ARENA.end());
+
+        unsafe {
+            allocator.dealloc(ptr, quarter);
+            let kept_bytes = core::slice::from_raw_parts(kept, small.size());
+            assert!(kept_bytes.iter().all(|&byte| byte == 0xAB));
+            allocator.dealloc(kept, small);
+        }
+        // Only the bitmap is not free.
+        assert!(allocator.heap_stats().free > ARENA.size() / 8 * 7);
+    }
+
+    #[test_case]
+    fn test_alloc_before_init() {
+        let allocator = Locked::new(BuddyAllocator::new());
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        assert!(unsafe { allocator.alloc(layout) }.is_null());
+    }
+}
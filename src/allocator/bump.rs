@@ -1,7 +1,9 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
-use super::{align_up, Locked};
+use super::{
+    align_up, realloc_by_copy, AllocatorStats, HeapAllocator, HeapStats, Locked, UsageCounters,
+};
 
 #[derive(Default)]
 pub struct BumpAllocator {
@@ -9,6 +11,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    counters: UsageCounters,
 }
 
 impl BumpAllocator {
@@ -20,6 +23,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            counters: UsageCounters::new(),
         }
     }
 
@@ -50,16 +54,64 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.counters.allocated(layout);
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock();
 
+        bump.counters.freed(layout);
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
+
+    /// The last allocation grows or shrinks in place.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut bump = self.lock();
+
+        let alloc_start = ptr as usize;
+        if alloc_start + layout.size() == bump.next {
+            if let Some(alloc_end) = alloc_start
+                .checked_add(new_size)
+                .filter(|&alloc_end| alloc_end <= bump.heap_end)
+            {
+                bump.next = alloc_end;
+                bump.counters.freed(layout);
+                bump.counters.allocated(unsafe {
+                    // SAFETY: the caller guarantees that the new size is valid for the alignment.
+                    Layout::from_size_align_unchecked(new_size, layout.align())
+                });
+                return ptr;
+            }
+        }
+
+        drop(bump);
+        unsafe { realloc_by_copy(self, ptr, layout, new_size) }
+    }
+}
+
+impl HeapAllocator for Locked<BumpAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        self.lock().heap_end += additional;
+    }
+}
+
+impl AllocatorStats for Locked<BumpAllocator> {
+    /// Only the memory after `next` is free, until every allocation is freed.
+    fn heap_stats(&self) -> HeapStats {
+        let bump = self.lock();
+        let free = bump.heap_end - bump.next;
+        bump.counters
+            .stats(bump.heap_end - bump.heap_start, free, Some(free))
+    }
 }
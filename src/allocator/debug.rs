@@ -0,0 +1,437 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+use super::{AllocatorStats, HeapAllocator, HeapStats};
+use crate::irq_mutex::IrqMutex;
+
+/// Written over new memory, to expose reads of uninitialized memory.
+pub const ALLOC_FILL: u8 = 0xAA;
+/// Written over freed memory, to expose uses after free.
+pub const FREE_FILL: u8 = 0xDD;
+/// Written before and after each block, checked when it is freed.
+pub const GUARD_FILL: u8 = 0xFD;
+pub const GUARD_SIZE: usize = 16;
+/// The return addresses recorded for each allocation.
+pub const CALLERS: usize = 8;
+/// Most bytes between two frames, a bigger step means that the chain ended.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const LIVE_MAGIC: u64 = 0xA11C_A7ED_A11C_A7ED;
+const FREED_MAGIC: u64 = 0xDEAD_F4EE_DEAD_F4EE;
+
+/// Kept just before each block, it ends with the leading guard bytes.
+#[repr(C)]
+struct Header {
+    previous: *mut Header,
+    next: *mut Header,
+    layout: Layout,
+    callers: Callers,
+    id: u64,
+    magic: u64,
+    guard: [u8; GUARD_SIZE],
+}
+
+/// A live allocation recorded by `DebugAlloc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    pub addr: usize,
+    pub layout: Layout,
+    pub callers: Callers,
+    /// The allocations are numbered in order, from 0.
+    pub id: u64,
+}
+
+impl fmt::Display for LiveAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {:#x}: {} bytes aligned to {}, from {}",
+            self.id,
+            self.addr,
+            self.layout.size(),
+            self.layout.align(),
+            self.callers
+        )
+    }
+}
+
+/// The return addresses of the calls leading to an allocation, the innermost
+/// first, 0 past the end of the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Callers(pub [usize; CALLERS]);
+
+impl Callers {
+    /// Walks the saved frame pointers from the caller of the function calling
+    /// it, the kernel is built with frame pointers.
+    #[inline(never)]
+    fn capture() -> Self {
+        let mut callers = [0; CALLERS];
+        let mut frame: usize;
+        unsafe {
+            // SAFETY: reads the frame pointer of this function.
+            asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags));
+        }
+        // The first frame is the one of the function calling `capture`.
+        for caller in &mut callers {
+            let Some(next) = next_frame(frame) else {
+                break;
+            };
+            frame = next;
+            *caller = unsafe {
+                // SAFETY: the return address follows the saved frame pointer.
+                *(frame as *const usize).add(1)
+            };
+        }
+        Self(callers)
+    }
+}
+
+/// The frame of the caller of the function whose frame is `frame`, if it is
+/// on the stack just above it.
+fn next_frame(frame: usize) -> Option<usize> {
+    let next = unsafe {
+        // SAFETY: `frame` is the frame of a running function, it starts with
+        // the saved frame pointer of its caller.
+        *(frame as *const usize)
+    };
+    (next > frame && next - frame <= MAX_FRAME_SIZE && next.is_multiple_of(8)).then_some(next)
+}
+
+impl fmt::Display for Callers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut callers = self.0.iter().take_while(|&&caller| caller != 0);
+        match callers.next() {
+            Some(first) => write!(f, "{first:#x}")?,
+            None => write!(f, "unknown")?,
+        }
+        for caller in callers {
+            write!(f, " < {caller:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+struct LiveList {
+    /// The most recent allocation.
+    head: *mut Header,
+    count: usize,
+    next_id: u64,
+}
+
+// SAFETY: the headers are only reached through the list lock.
+unsafe impl Send for LiveList {}
+
+/// The allocations of a `DebugAlloc` that are not freed yet.
+pub struct LiveAllocations {
+    list: IrqMutex<LiveList>,
+}
+
+impl LiveAllocations {
+    const fn new() -> Self {
+        Self {
+            list: IrqMutex::new(LiveList {
+                head: ptr::null_mut(),
+                count: 0,
+                next_id: 0,
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.list.lock().count
+    }
+
+    /// The id of the next allocation, to find the ones made after this call.
+    #[must_use]
+    pub fn next_id(&self) -> u64 {
+        self.list.lock().next_id
+    }
+
+    /// Calls `f` on the live allocations with an id of at least `since`,
+    /// the most recent first.
+    ///
+    /// The allocator is locked during the calls, `f` must not allocate.
+    pub fn for_each_since(&self, since: u64, mut f: impl FnMut(LiveAllocation)) {
+        let list = self.list.lock();
+        let mut current = list.head;
+        while !current.is_null() {
+            let header = unsafe {
+                // SAFETY: the headers of the list are live.
+                &*current
+            };
+            if header.id < since {
+                // The list is sorted by decreasing id.
+                break;
+            }
+            f(LiveAllocation {
+                addr: current as usize + mem::size_of::<Header>(),
+                layout: header.layout,
+                callers: header.callers,
+                id: header.id,
+            });
+            current = header.next;
+        }
+    }
+
+    fn push(&self, header: &mut Header) {
+        let mut list = self.list.lock();
+        header.id = list.next_id;
+        header.previous = ptr::null_mut();
+        header.next = list.head;
+        if let Some(next) = unsafe { list.head.as_mut() } {
+            next.previous = header;
+        }
+        list.head = header;
+        list.count += 1;
+        list.next_id += 1;
+    }
+
+    fn remove(&self, header: &mut Header) {
+        let mut list = self.list.lock();
+        match unsafe { header.previous.as_mut() } {
+            Some(previous) => previous.next = header.next,
+            None => list.head = header.next,
+        }
+        if let Some(next) = unsafe { header.next.as_mut() } {
+            next.previous = header.previous;
+        }
+        list.count -= 1;
+    }
+}
+
+/// Wraps an allocator to catch memory errors.
+///
+/// New memory is filled with `ALLOC_FILL` and freed memory with `FREE_FILL`.
+/// Each block is surrounded by `GUARD_SIZE` guard bytes checked when it is
+/// freed, and its header records the calls leading to the allocation and
+/// detects double frees.
+pub struct DebugAlloc<A> {
+    inner: A,
+    live: LiveAllocations,
+}
+
+impl<A> DebugAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: LiveAllocations::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn live(&self) -> &LiveAllocations {
+        &self.live
+    }
+
+    /// The layout asked to the inner allocator and the offset of the block in it.
+    fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let offset = mem::size_of::<Header>().next_multiple_of(align);
+        let size = offset.checked_add(layout.size())?.checked_add(GUARD_SIZE)?;
+        Some((Layout::from_size_align(size, align).ok()?, offset))
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = Callers::capture();
+        let Some((outer_layout, offset)) = Self::outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let base = unsafe { self.inner.alloc(outer_layout) };
+        if base.is_null() {
+            return base;
+        }
+
+        unsafe {
+            // SAFETY: the inner block has room for the header, the block and
+            // the trailing guard bytes.
+            let ptr = base.add(offset);
+            // The offset keeps the header aligned.
+            #[allow(clippy::cast_ptr_alignment)]
+            let header_ptr = ptr.sub(mem::size_of::<Header>()).cast::<Header>();
+            header_ptr.write(Header {
+                previous: ptr::null_mut(),
+                next: ptr::null_mut(),
+                layout,
+                callers,
+                id: 0,
+                magic: LIVE_MAGIC,
+                guard: [GUARD_FILL; GUARD_SIZE],
+            });
+            ptr.write_bytes(ALLOC_FILL, layout.size());
+            ptr.add(layout.size()).write_bytes(GUARD_FILL, GUARD_SIZE);
+            self.live.push(&mut *header_ptr);
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[allow(clippy::cast_ptr_alignment)]
+        let header = unsafe {
+            // SAFETY: the caller guarantees that the pointer comes from `alloc`.
+            &mut *ptr.sub(mem::size_of::<Header>()).cast::<Header>()
+        };
+        match header.magic {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => panic!("double free of {ptr:p}, allocated from {}", header.callers),
+            _ => panic!("free of {ptr:p} which is not allocated or has a corrupted header"),
+        }
+        assert_eq!(
+            header.layout, layout,
+            "{ptr:p} freed with another layout than allocated from {}",
+            header.callers
+        );
+
+        let trailer = unsafe {
+            // SAFETY: the guard bytes follow the block.
+            core::slice::from_raw_parts(ptr.add(layout.size()), GUARD_SIZE)
+        };
+        let guards_intact = header
+            .guard
+            .iter()
+            .chain(trailer)
+            .all(|&byte| byte == GUARD_FILL);
+        assert!(
+            guards_intact,
+            "guard bytes of {ptr:p} ({} bytes allocated from {}) were overwritten",
+            layout.size(),
+            header.callers
+        );
+
+        self.live.remove(header);
+        header.magic = FREED_MAGIC;
+        let (outer_layout, offset) = Self::outer_layout(layout).unwrap();
+        unsafe {
+            // SAFETY: the block is not used anymore.
+            ptr.write_bytes(FREE_FILL, layout.size());
+            self.inner.dealloc(ptr.sub(offset), outer_layout);
+        }
+    }
+}
+
+impl<A: HeapAllocator> HeapAllocator for DebugAlloc<A> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.inner.init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.inner.extend(additional);
+        }
+    }
+}
+
+impl<A: AllocatorStats> AllocatorStats for DebugAlloc<A> {
+    /// The sizes include the headers and the guard bytes.
+    fn heap_stats(&self) -> HeapStats {
+        self.inner.heap_stats()
+    }
+
+    fn dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "{} live allocations", self.live.count())?;
+        self.inner.dump(out)
+    }
+}
+
+#[cfg(test)]
+mod debug_tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::allocator::linked_list::{FirstFit, LinkedListAllocator};
+    use crate::allocator::Locked;
+    use crate::test_utils::Arena;
+    use crate::{assert, assert_eq};
+
+    static ARENA: Arena<{ 16 * 1024 }> = Arena::new();
+
+    fn allocator() -> DebugAlloc<Locked<LinkedListAllocator<FirstFit>>> {
+        let allocator = DebugAlloc::new(Locked::new(LinkedListAllocator::new()));
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size());
+        }
+        allocator
+    }
+
+    #[test_case]
+    fn test_fill_and_guards() {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(24, 64).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert_eq!(ptr as usize % 64, 0);
+
+        let block = unsafe { core::slice::from_raw_parts(ptr, 24 + GUARD_SIZE) };
+        assert!(block[..24].iter().all(|&byte| byte == ALLOC_FILL));
+        assert!(block[24..].iter().all(|&byte| byte == GUARD_FILL));
+
+        unsafe { allocator.dealloc(ptr, layout) };
+        // The inner allocator writes its free list node before the block.
+        assert!(block[..24].iter().all(|&byte| byte == FREE_FILL));
+    }
+
+    #[test_case]
+    fn test_live_allocations() {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let blocks: Vec<_> = (0..4).map(|_| unsafe { allocator.alloc(layout) }).collect();
+        let marker = allocator.live().next_id();
+        let recent = unsafe { allocator.alloc(layout) };
+
+        unsafe { allocator.dealloc(blocks[1], layout) };
+        assert_eq!(allocator.live().count(), 4);
+
+        let mut since_marker = Vec::new();
+        allocator
+            .live()
+            .for_each_since(marker, |allocation| since_marker.push(allocation.addr));
+        assert_eq!(since_marker, [recent as usize]);
+
+        let mut ids = Vec::new();
+        allocator
+            .live()
+            .for_each_since(0, |allocation| ids.push(allocation.id));
+        assert_eq!(ids, [4, 3, 2, 0]);
+
+        for ptr in [blocks[0], blocks[2], blocks[3], recent] {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(allocator.live().count(), 0);
+    }
+
+    #[inline(never)]
+    fn alloc_in_helper(allocator: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+        unsafe { allocator.alloc(layout) }
+    }
+
+    #[test_case]
+    fn test_callers() {
+        let allocator = allocator();
+        let layout = Layout::from_size_align(8, 8).unwrap();
+        let first = alloc_in_helper(&allocator, layout);
+        let second = alloc_in_helper(&allocator, layout);
+
+        let mut callers = Vec::new();
+        allocator
+            .live()
+            .for_each_since(0, |allocation| callers.push(allocation.callers.0));
+        // The calls of the helper come from two places of the test.
+        assert_eq!(callers[0][0], callers[1][0]);
+        assert!(callers[0][1] != 0 && callers[0][1] != callers[1][1]);
+        assert_eq!(callers[0][2..], callers[1][2..]);
+
+        for ptr in [first, second] {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+}
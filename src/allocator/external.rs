@@ -0,0 +1,76 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use linked_list_allocator::Heap;
+
+use super::{AllocatorStats, HeapAllocator, HeapStats, Locked, UsageCounters};
+
+/// The `linked_list_allocator` crate heap, with usage counters.
+pub struct ExternalHeap {
+    heap: Heap,
+    counters: UsageCounters,
+}
+
+impl Default for ExternalHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExternalHeap {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            heap: Heap::empty(),
+            counters: UsageCounters::new(),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalHeap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match allocator.heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                allocator.counters.allocated(layout);
+                ptr.as_ptr()
+            }
+            Err(()) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.counters.freed(layout);
+        // We can unwrap because the pointer is non null.
+        let ptr = NonNull::new(ptr).unwrap();
+        unsafe {
+            // SAFETY: the pointer comes from this heap.
+            allocator.heap.deallocate(ptr, layout);
+        }
+    }
+}
+
+impl HeapAllocator for Locked<ExternalHeap> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().heap.init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.lock().heap.extend(additional);
+        }
+    }
+}
+
+impl AllocatorStats for Locked<ExternalHeap> {
+    /// The crate does not expose its holes, the largest free block is unknown.
+    fn heap_stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        allocator
+            .counters
+            .stats(allocator.heap.size(), allocator.heap.free(), None)
+    }
+}
@@ -1,9 +1,10 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
 use core::mem;
 use core::ptr;
 use core::ptr::NonNull;
 
-use super::Locked;
+use super::{realloc_by_copy, AllocatorStats, HeapAllocator, HeapStats, Locked, UsageCounters};
 
 /// The block sizes to use.
 ///
@@ -15,17 +16,55 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+/// Default maximum number of free blocks kept by each size class.
+pub const DEFAULT_FREE_WATERMARK: usize = 64;
+
+/// Counters of one size class.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStats {
+    pub block_size: usize,
+    pub in_use: usize,
+    /// Blocks kept in the class list.
+    pub free: usize,
+    /// Blocks taken from the fallback allocator.
+    pub carved: u64,
+    /// Allocations served from the class list.
+    pub reused: u64,
+    /// Blocks given back to the fallback allocator.
+    pub returned: u64,
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    class_stats: [ClassStats; BLOCK_SIZES.len()],
+    free_watermark: Option<usize>,
+    counters: UsageCounters,
 }
 
 impl FixedSizeBlockAllocator {
     #[must_use]
     pub const fn new() -> Self {
+        let mut class_stats = [ClassStats {
+            block_size: 0,
+            in_use: 0,
+            free: 0,
+            carved: 0,
+            reused: 0,
+            returned: 0,
+        }; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            class_stats[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+
         Self {
             list_heads: [const { None }; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            class_stats,
+            free_watermark: Some(DEFAULT_FREE_WATERMARK),
+            counters: UsageCounters::new(),
         }
     }
 
@@ -40,6 +79,40 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// Sets the maximum number of free blocks kept by each class, the blocks
+    /// freed above it go back to the fallback allocator. `None` keeps them all.
+    pub fn set_free_watermark(&mut self, free_watermark: Option<usize>) {
+        self.free_watermark = free_watermark;
+    }
+
+    /// Gives every free block back to the fallback allocator, so that the
+    /// memory can serve any size.
+    ///
+    /// Returns the number of bytes given back.
+    pub fn trim(&mut self) -> usize {
+        let mut trimmed = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe {
+                    // SAFETY: the block was allocated from the fallback
+                    // allocator with this layout.
+                    self.fallback_allocator.deallocate(ptr, block_layout(index));
+                }
+                self.class_stats[index].free -= 1;
+                self.class_stats[index].returned += 1;
+                trimmed += block_size;
+            }
+        }
+        trimmed
+    }
+
+    #[must_use]
+    pub fn class_stats(&self) -> &[ClassStats] {
+        &self.class_stats
+    }
+
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
@@ -63,30 +136,68 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// The layout of the blocks of a class, as allocated from the fallback allocator.
+fn block_layout(index: usize) -> Layout {
+    let block_size = BLOCK_SIZES[index];
+    // only works if all block sizes are a power of 2
+    let block_align = block_size;
+    Layout::from_size_align(block_size, block_align).unwrap()
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 if let Some(node) = allocator.list_heads[index].take() {
                     allocator.list_heads[index] = node.next.take();
+                    let stats = &mut allocator.class_stats[index];
+                    stats.free -= 1;
+                    stats.in_use += 1;
+                    stats.reused += 1;
                     core::ptr::from_mut::<ListNode>(node).cast::<u8>()
                 } else {
                     // no block exists in list => allocate new block
-                    let block_size = BLOCK_SIZES[index];
-                    // only works if all block sizes are a power of 2
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
+                    let ptr = allocator.fallback_alloc(block_layout(index));
+                    if !ptr.is_null() {
+                        let stats = &mut allocator.class_stats[index];
+                        stats.in_use += 1;
+                        stats.carved += 1;
+                    }
+                    ptr
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.counters.allocated(layout);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.counters.freed(layout);
         if let Some(index) = list_index(&layout) {
+            allocator.class_stats[index].in_use -= 1;
+            if allocator
+                .free_watermark
+                .is_some_and(|watermark| allocator.class_stats[index].free >= watermark)
+            {
+                // The class keeps enough free blocks, the memory goes back
+                // to the fallback allocator.
+                allocator.class_stats[index].returned += 1;
+                let ptr = NonNull::new(ptr).unwrap();
+                unsafe {
+                    // SAFETY: the block was allocated from the fallback
+                    // allocator with this layout.
+                    allocator
+                        .fallback_allocator
+                        .deallocate(ptr, block_layout(index));
+                }
+                return;
+            }
+
             let new_node = ListNode {
                 next: allocator.list_heads[index].take(),
             };
@@ -99,6 +210,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
+            allocator.class_stats[index].free += 1;
         } else {
             // We can unwrap because the pointer is non null.
             let ptr = NonNull::new(ptr).unwrap();
@@ -108,4 +220,166 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
+
+    /// A block stays in place while the new size fits the same class.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe {
+            // SAFETY: the caller guarantees that the new size is valid for the alignment.
+            Layout::from_size_align_unchecked(new_size, layout.align())
+        };
+        let index = list_index(&layout);
+        if index.is_some() && index == list_index(&new_layout) {
+            let mut allocator = self.lock();
+            allocator.counters.freed(layout);
+            allocator.counters.allocated(new_layout);
+            return ptr;
+        }
+
+        unsafe { realloc_by_copy(self, ptr, layout, new_size) }
+    }
+}
+
+impl HeapAllocator for Locked<FixedSizeBlockAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.lock().fallback_allocator.extend(additional);
+        }
+    }
+}
+
+impl AllocatorStats for Locked<FixedSizeBlockAllocator> {
+    /// The free memory counts the blocks kept by the classes. The fallback
+    /// allocator does not expose its holes, the largest free block is unknown.
+    fn heap_stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        let kept: usize = allocator
+            .class_stats
+            .iter()
+            .map(|stats| stats.free * stats.block_size)
+            .sum();
+        allocator.counters.stats(
+            allocator.fallback_allocator.size(),
+            allocator.fallback_allocator.free() + kept,
+            None,
+        )
+    }
+
+    fn dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        for stats in self.lock().class_stats() {
+            writeln!(
+                out,
+                "  {:>4} bytes: {} in use, {} free, {} carved, {} reused, {} returned",
+                stats.block_size,
+                stats.in_use,
+                stats.free,
+                stats.carved,
+                stats.reused,
+                stats.returned
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod fixed_size_block_tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::test_utils::Arena;
+    use crate::{assert, assert_eq};
+
+    static ARENA: Arena<{ 16 * 1024 }> = Arena::new();
+
+    fn allocator(free_watermark: Option<usize>) -> Locked<FixedSizeBlockAllocator> {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        allocator.lock().set_free_watermark(free_watermark);
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size());
+        }
+        allocator
+    }
+
+    /// Allocates blocks with the given layout until the heap is full.
+    fn fill(allocator: &Locked<FixedSizeBlockAllocator>, layout: Layout) -> Vec<*mut u8> {
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                return blocks;
+            }
+            blocks.push(ptr);
+        }
+    }
+
+    #[test_case]
+    fn test_trim_gives_memory_back() {
+        let allocator = allocator(None);
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let large = Layout::from_size_align(2048, 8).unwrap();
+
+        let blocks = fill(&allocator, small);
+        for &ptr in &blocks {
+            unsafe { allocator.dealloc(ptr, small) };
+        }
+        assert!(unsafe { allocator.alloc(large) }.is_null());
+
+        let stats = allocator.lock().class_stats()[0];
+        assert_eq!(stats.free, blocks.len());
+        assert_eq!(stats.in_use, 0);
+
+        assert_eq!(allocator.lock().trim(), blocks.len() * 8);
+        assert_eq!(allocator.lock().class_stats()[0].free, 0);
+        assert!(!unsafe { allocator.alloc(large) }.is_null());
+    }
+
+    #[test_case]
+    fn test_free_watermark() {
+        let allocator = allocator(Some(4));
+        let layout = Layout::from_size_align(16, 8).unwrap();
+
+        let blocks: Vec<_> = (0..16)
+            .map(|_| unsafe { allocator.alloc(layout) })
+            .collect();
+        for &ptr in &blocks {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        let stats = allocator.lock().class_stats()[1];
+        assert_eq!(
+            stats,
+            ClassStats {
+                block_size: 16,
+                in_use: 0,
+                free: 4,
+                carved: 16,
+                reused: 0,
+                returned: 12,
+            }
+        );
+
+        // The kept blocks are reused first.
+        let _ = unsafe { allocator.alloc(layout) };
+        assert_eq!(allocator.lock().class_stats()[1].reused, 1);
+    }
+
+    #[test_case]
+    fn test_realloc_in_same_class() {
+        let allocator = allocator(None);
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+
+        assert_eq!(unsafe { allocator.realloc(ptr, layout, 32) }, ptr);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let moved = unsafe { allocator.realloc(ptr, layout, 33) };
+        assert!(moved != ptr);
+        assert_eq!(allocator.lock().class_stats()[2].in_use, 0);
+        assert_eq!(allocator.lock().class_stats()[3].in_use, 1);
+    }
 }
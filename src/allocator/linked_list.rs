@@ -1,10 +1,14 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
 use core::mem;
 use core::ptr;
 
-use super::Locked;
+use super::{realloc_by_copy, AllocatorStats, HeapAllocator, HeapStats, Locked, UsageCounters};
 use crate::allocator::fast_align_up;
 
+/// The regions printed by `dump`, the list can be long.
+const MAX_DUMPED_REGIONS: usize = 32;
+
 #[derive(Default)]
 struct ListNode {
     size: usize,
@@ -26,16 +30,113 @@ impl ListNode {
     }
 }
 
-#[derive(Default)]
-pub struct LinkedListAllocator {
+/// Chooses the free region an allocation is carved from.
+///
+/// The regions are searched by increasing address from `search_start`,
+/// wrapping around to the lower addresses.
+pub trait Placement {
+    const NEW: Self;
+    /// Whether the search ends on the first region big enough.
+    const STOP_AT_FIRST_FIT: bool;
+
+    fn search_start(&self) -> usize {
+        0
+    }
+
+    /// Called with the end address of each successful allocation.
+    fn allocated(&mut self, _alloc_end: usize) {}
+}
+
+/// Takes the first region big enough, by address.
+pub struct FirstFit;
+
+impl Placement for FirstFit {
+    const NEW: Self = Self;
+    const STOP_AT_FIRST_FIT: bool = true;
+}
+
+/// Like `FirstFit` but the search resumes where the previous allocation ended.
+pub struct NextFit {
+    rover: usize,
+}
+
+impl Placement for NextFit {
+    const NEW: Self = Self { rover: 0 };
+    const STOP_AT_FIRST_FIT: bool = true;
+
+    fn search_start(&self) -> usize {
+        self.rover
+    }
+
+    fn allocated(&mut self, alloc_end: usize) {
+        self.rover = alloc_end;
+    }
+}
+
+/// Takes the smallest region big enough.
+pub struct BestFit;
+
+impl Placement for BestFit {
+    const NEW: Self = Self;
+    const STOP_AT_FIRST_FIT: bool = false;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkedListStats {
+    pub searches: u64,
+    /// Sum of the regions examined by all searches.
+    pub visited_regions: u64,
+    pub longest_search: u64,
+    pub free_regions: usize,
+    pub free_bytes: usize,
+    pub largest_free_region: usize,
+}
+
+impl LinkedListStats {
+    #[must_use]
+    pub fn average_search_length(&self) -> u64 {
+        self.visited_regions.checked_div(self.searches).unwrap_or(0)
+    }
+
+    /// The part of the free memory that is not in the largest free region.
+    #[must_use]
+    pub fn fragmentation_percent(&self) -> usize {
+        match self.free_bytes {
+            0 => 0,
+            free_bytes => 100 - self.largest_free_region * 100 / free_bytes,
+        }
+    }
+}
+
+pub struct LinkedListAllocator<P = FirstFit> {
     head: ListNode,
+    placement: P,
+    heap_start: usize,
+    heap_size: usize,
+    counters: UsageCounters,
+    searches: u64,
+    visited_regions: u64,
+    longest_search: u64,
+}
+
+impl<P: Placement> Default for LinkedListAllocator<P> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl LinkedListAllocator {
+impl<P: Placement> LinkedListAllocator<P> {
     #[must_use]
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            placement: P::NEW,
+            heap_start: 0,
+            heap_size: 0,
+            counters: UsageCounters::new(),
+            searches: 0,
+            visited_regions: 0,
+            longest_search: 0,
         }
     }
 
@@ -47,47 +148,201 @@ impl LinkedListAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
         unsafe {
             self.add_free_region(heap_start, heap_size);
         }
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Adds the `additional` bytes following the heap to the free list.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory following the heap is valid
+    /// and unused.
+    pub unsafe fn extend(&mut self, additional: usize) {
+        let heap_end = self.heap_start + self.heap_size;
+        self.heap_size += additional;
+        unsafe {
+            self.add_free_region(heap_end, additional);
+        }
+    }
+
+    /// Adds the given memory region to the list, which is kept sorted by address,
+    /// and merges it with its free neighbours.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // Ensure that the freed region is capable of holding a `ListNode`.
         assert_eq!(fast_align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // Create a new `ListNode` and append it at the start of the list.
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        unsafe {
-            // SAFETY: the memory is unsused and has enough space.
-            node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr);
+        // Find the last region starting before the freed one.
+        let mut previous = &mut self.head;
+        let mut previous_is_head = true;
+        while previous
+            .next
+            .as_ref()
+            .is_some_and(|next| next.get_start_addr() < addr)
+        {
+            previous = previous.next.as_mut().unwrap();
+            previous_is_head = false;
+        }
+
+        let mut size = size;
+        let mut next = previous.next.take();
+        if let Some(next_region) = next.take_if(|next| next.get_start_addr() == addr + size) {
+            size += next_region.size;
+            next = next_region.next.take();
+        }
+
+        // The head is not part of the heap, it is never merged.
+        if !previous_is_head && previous.get_end_addr() == addr {
+            previous.size += size;
+            previous.next = next;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            unsafe {
+                // SAFETY: the memory is unsused and has enough space.
+                node_ptr.write(node);
+                previous.next = Some(&mut *node_ptr);
+            }
         }
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
-        let mut current = &mut self.head;
-
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
+        let search_start = self.placement.search_start();
+        // The address, size and allocation start of the chosen region.
+        let mut chosen: Option<(usize, usize, usize)> = None;
+        let mut visited = 0;
+
+        // The regions from `search_start`, then the ones before it.
+        'search: for wrapped in [false, true] {
+            let mut current = self.head.next.as_deref();
+            while let Some(region) = current {
+                current = region.next.as_deref();
+                let is_before_start = region.get_start_addr() < search_start;
+                if wrapped && !is_before_start {
+                    break;
+                } else if !wrapped && is_before_start {
+                    continue;
+                }
+
+                visited += 1;
+                if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                    if chosen.is_none_or(|(_, chosen_size, _)| region.size < chosen_size) {
+                        chosen = Some((region.get_start_addr(), region.size, alloc_start));
+                    }
+                    if P::STOP_AT_FIRST_FIT {
+                        break 'search;
+                    }
+                }
             }
+        }
 
-            current = current.next.as_mut().unwrap();
+        self.searches += 1;
+        self.visited_regions += visited;
+        self.longest_search = self.longest_search.max(visited);
+
+        let (region_addr, _, alloc_start) = chosen?;
+        Some((self.remove_region(region_addr), alloc_start))
+    }
+
+    /// Unlinks the region starting at `addr`, which must be in the list.
+    fn remove_region(&mut self, addr: usize) -> &'static mut ListNode {
+        let mut previous = &mut self.head;
+        while previous
+            .next
+            .as_ref()
+            .is_some_and(|next| next.get_start_addr() != addr)
+        {
+            previous = previous.next.as_mut().unwrap();
         }
 
-        None
+        let region = previous.next.take().expect("region not in the free list");
+        previous.next = region.next.take();
+        region
+    }
+
+    /// Resizes the block at `addr` from `size` to `new_size` bytes, both
+    /// adjusted by `size_align`, without moving it.
+    ///
+    /// A block grows into the free region that follows it. Returns whether
+    /// the block could be resized.
+    unsafe fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let block_end = addr + size;
+        if new_size <= size {
+            let excess_size = size - new_size;
+            if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+                return false;
+            }
+            if excess_size > 0 {
+                unsafe {
+                    // SAFETY: the end of the block is not used anymore.
+                    self.add_free_region(addr + new_size, excess_size);
+                }
+            }
+            return true;
+        }
+
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            if region.get_start_addr() >= block_end {
+                break;
+            }
+            current = region.next.as_deref();
+        }
+        let Some(region) = current.filter(|region| region.get_start_addr() == block_end) else {
+            return false;
+        };
+        let Some(excess_size) = region.size.checked_sub(new_size - size) else {
+            return false;
+        };
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return false;
+        }
+
+        self.remove_region(block_end);
+        if excess_size > 0 {
+            unsafe {
+                // SAFETY: the end of the region is still unused.
+                self.add_free_region(addr + new_size, excess_size);
+            }
+        }
+        true
+    }
+
+    /// Returns the search counters and the shape of the free list.
+    #[must_use]
+    pub fn stats(&self) -> LinkedListStats {
+        let mut stats = LinkedListStats {
+            searches: self.searches,
+            visited_regions: self.visited_regions,
+            longest_search: self.longest_search,
+            ..LinkedListStats::default()
+        };
+
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            stats.free_regions += 1;
+            stats.free_bytes += region.size;
+            stats.largest_free_region = stats.largest_free_region.max(region.size);
+            current = region.next.as_deref();
+        }
+
+        stats
     }
 
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = fast_align_up(region.get_start_addr(), align);
+        let mut alloc_start = fast_align_up(region.get_start_addr(), align);
+
+        // The memory skipped for the alignment goes back to the list,
+        // it must be able to hold a `ListNode`.
+        let skipped_size = alloc_start - region.get_start_addr();
+        if skipped_size > 0 && skipped_size < mem::size_of::<ListNode>() {
+            alloc_start = fast_align_up(alloc_start + mem::size_of::<ListNode>(), align);
+        }
 
         let Some(alloc_end) = alloc_start.checked_add(size) else {
             return Err(());
@@ -120,20 +375,30 @@ impl LinkedListAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+unsafe impl<P: Placement> GlobalAlloc for Locked<LinkedListAllocator<P>> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
+        let (size, align) = LinkedListAllocator::<P>::size_align(layout);
         let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.get_end_addr() - alloc_end;
+            let (region_start, region_end) = (region.get_start_addr(), region.get_end_addr());
+            let skipped_size = alloc_start - region_start;
+            if skipped_size > 0 {
+                unsafe {
+                    // SAFETY: the memory is unsused and has enough space.
+                    allocator.add_free_region(region_start, skipped_size);
+                }
+            }
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 unsafe {
                     // SAFETY: the memory is unsused and has enough space.
                     allocator.add_free_region(alloc_end, excess_size);
                 }
             }
+            allocator.placement.allocated(alloc_end);
+            allocator.counters.allocated(layout);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -141,11 +406,171 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
+        let (size, _) = LinkedListAllocator::<P>::size_align(layout);
+        let mut allocator = self.lock();
 
+        allocator.counters.freed(layout);
         unsafe {
             // SAFETY: the memory is unsused and has enough space.
-            self.lock().add_free_region(ptr as usize, size);
+            allocator.add_free_region(ptr as usize, size);
+        }
+    }
+
+    /// A block grows into the free region that follows it and gives its
+    /// end back when it shrinks.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe {
+            // SAFETY: the caller guarantees that the new size is valid for the alignment.
+            Layout::from_size_align_unchecked(new_size, layout.align())
+        };
+        let (size, _) = LinkedListAllocator::<P>::size_align(layout);
+        let (adjusted_new_size, _) = LinkedListAllocator::<P>::size_align(new_layout);
+        let mut allocator = self.lock();
+
+        if unsafe { allocator.resize_in_place(ptr as usize, size, adjusted_new_size) } {
+            allocator.counters.freed(layout);
+            allocator.counters.allocated(new_layout);
+            return ptr;
+        }
+
+        drop(allocator);
+        unsafe { realloc_by_copy(self, ptr, layout, new_size) }
+    }
+}
+
+impl<P: Placement> HeapAllocator for Locked<LinkedListAllocator<P>> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().init(heap_start, heap_size);
         }
     }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.lock().extend(additional);
+        }
+    }
+}
+
+impl<P: Placement> AllocatorStats for Locked<LinkedListAllocator<P>> {
+    fn heap_stats(&self) -> HeapStats {
+        let allocator = self.lock();
+        let list = allocator.stats();
+        allocator.counters.stats(
+            allocator.heap_size,
+            list.free_bytes,
+            Some(list.largest_free_region),
+        )
+    }
+
+    fn dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let allocator = self.lock();
+        let stats = allocator.stats();
+        writeln!(
+            out,
+            "{} free regions, average search {}, longest {}",
+            stats.free_regions,
+            stats.average_search_length(),
+            stats.longest_search
+        )?;
+
+        let mut current = allocator.head.next.as_deref();
+        for _ in 0..MAX_DUMPED_REGIONS {
+            let Some(region) = current else {
+                return Ok(());
+            };
+            writeln!(
+                out,
+                "  {:#x}..{:#x} {} bytes",
+                region.get_start_addr(),
+                region.get_end_addr(),
+                region.size
+            )?;
+            current = region.next.as_deref();
+        }
+        if current.is_some() {
+            writeln!(out, "  ...")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod linked_list_tests {
+    use alloc::alloc::{GlobalAlloc, Layout};
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::test_utils::prop::{self, Generator, Rng};
+    use crate::test_utils::Arena;
+    use crate::{assert, assert_eq};
+
+    static ARENA: Arena<{ 16 * 1024 }> = Arena::new();
+
+    /// Fills the heap with blocks of random sizes and alignments, frees them
+    /// in random order then allocates the whole heap at once.
+    #[test_case]
+    fn test_coalescing_after_random_frees() {
+        let config = prop::Config {
+            cases: 20,
+            ..prop::Config::default()
+        };
+        config.check(&(0..=u64::MAX), |&seed| {
+            let mut rng = Rng::new(seed);
+            let allocator = Locked::new(LinkedListAllocator::<FirstFit>::new());
+            unsafe {
+                // SAFETY: the previous allocator of the arena is dropped.
+                allocator.init(ARENA.start(), ARENA.size());
+            }
+
+            let mut blocks = Vec::new();
+            loop {
+                let size = (1..=300_usize).generate(&mut rng);
+                let align = 1 << (0..=6_u32).generate(&mut rng);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = unsafe { allocator.alloc(layout) };
+                if ptr.is_null() {
+                    break;
+                }
+                blocks.push((ptr, layout));
+            }
+
+            rng.shuffle(&mut blocks);
+            for (ptr, layout) in blocks {
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+
+            let whole_heap = Layout::from_size_align(ARENA.size(), 8).unwrap();
+            let ptr = unsafe { allocator.alloc(whole_heap) };
+            assert_eq!(ptr as usize, ARENA.start());
+            assert!(allocator.lock().head.next.is_none());
+        });
+    }
+
+    #[test_case]
+    fn test_realloc_in_place() {
+        let allocator = Locked::new(LinkedListAllocator::<FirstFit>::new());
+        unsafe {
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size());
+        }
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let first = unsafe { allocator.alloc(small) };
+        let second = unsafe { allocator.alloc(small) };
+
+        // The second block is followed by free memory, it grows in place.
+        let grown = unsafe { allocator.realloc(second, small, 1024) };
+        assert_eq!(grown, second);
+
+        // The first block is followed by the second one, it moves.
+        let moved = unsafe { allocator.realloc(first, small, 128) };
+        assert!(moved != first);
+
+        // Shrinking gives the end back.
+        let large = Layout::from_size_align(1024, 8).unwrap();
+        let shrunk = unsafe { allocator.realloc(grown, large, 64) };
+        assert_eq!(shrunk, second);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.free_bytes, ARENA.size() - 64 - 128);
+    }
 }
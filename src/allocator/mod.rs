@@ -1,45 +1,468 @@
+pub mod buddy;
 pub mod bump;
+pub mod debug;
+pub mod external;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
 
 use bootloader::bootinfo::MemoryMap;
 use linked_list_allocator::LockedHeap;
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
+use crate::irq_mutex::{IrqMutex, IrqMutexGuard};
 use crate::memory;
+use crate::memory::vmalloc::VmallocError;
 
-pub const HEAP_START: usize = 0x4444_4444_0000;
+/// Aligned on 2 MiB, the large extensions of the heap are mapped with huge
+/// pages.
+pub const HEAP_START: usize = 0x4444_4440_0000;
+/// The size mapped at boot.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// The size of the virtual range reserved for the heap, it is mapped on demand.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+/// The minimum size mapped each time the heap grows.
+pub const HEAP_GROWTH: usize = 64 * 1024; // 64 KiB
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-buddy",
+    feature = "alloc-external"
+)))]
+compile_error!("one of the `alloc-*` features must be enabled");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-buddy"),
+    all(feature = "alloc-bump", feature = "alloc-external"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-buddy"),
+    all(feature = "alloc-linked-list", feature = "alloc-external"),
+    all(feature = "alloc-fixed-block", feature = "alloc-buddy"),
+    all(feature = "alloc-fixed-block", feature = "alloc-external"),
+    all(feature = "alloc-buddy", feature = "alloc-external")
+))]
+compile_error!("only one of the `alloc-*` features can be enabled");
+
+#[cfg(feature = "alloc-bump")]
+type SelectedAllocator = Locked<bump::BumpAllocator>;
+#[cfg(feature = "alloc-bump")]
+const fn selected_allocator() -> SelectedAllocator {
+    Locked::new(bump::BumpAllocator::new())
+}
+#[cfg(feature = "alloc-bump")]
+pub const HEAP_ALLOCATOR_NAME: &str = "bump";
 
-// use bump::BumpAllocator;
-// use linked_list::LinkedListAllocator;
-// use fixed_size_block::FixedSizeBlockAllocator;
-// static HEAP_ALLOCATOR: Locked<FixedSizeBlockAllocator> =
-//     Locked::new(FixedSizeBlockAllocator::new());
+#[cfg(feature = "alloc-linked-list")]
+type SelectedAllocator = Locked<linked_list::LinkedListAllocator>;
+#[cfg(feature = "alloc-linked-list")]
+const fn selected_allocator() -> SelectedAllocator {
+    Locked::new(linked_list::LinkedListAllocator::new())
+}
+#[cfg(feature = "alloc-linked-list")]
+pub const HEAP_ALLOCATOR_NAME: &str = "linked list";
+
+#[cfg(feature = "alloc-fixed-block")]
+type SelectedAllocator = Locked<fixed_size_block::FixedSizeBlockAllocator>;
+#[cfg(feature = "alloc-fixed-block")]
+const fn selected_allocator() -> SelectedAllocator {
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new())
+}
+#[cfg(feature = "alloc-fixed-block")]
+pub const HEAP_ALLOCATOR_NAME: &str = "fixed size block";
+
+#[cfg(feature = "alloc-buddy")]
+type SelectedAllocator = Locked<buddy::BuddyAllocator>;
+#[cfg(feature = "alloc-buddy")]
+const fn selected_allocator() -> SelectedAllocator {
+    Locked::new(buddy::BuddyAllocator::new())
+}
+#[cfg(feature = "alloc-buddy")]
+pub const HEAP_ALLOCATOR_NAME: &str = "buddy";
+
+#[cfg(feature = "alloc-external")]
+type SelectedAllocator = Locked<external::ExternalHeap>;
+#[cfg(feature = "alloc-external")]
+const fn selected_allocator() -> SelectedAllocator {
+    Locked::new(external::ExternalHeap::new())
+}
+#[cfg(feature = "alloc-external")]
+pub const HEAP_ALLOCATOR_NAME: &str = "linked_list_allocator";
+
+#[cfg(not(feature = "alloc-debug"))]
+#[global_allocator]
+static HEAP_ALLOCATOR: KernelHeap<SelectedAllocator> = KernelHeap::new(selected_allocator());
+
+// The `alloc-debug` feature checks every allocation of the selected allocator.
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap<debug::DebugAlloc<SelectedAllocator>> =
+    KernelHeap::new(debug::DebugAlloc::new(selected_allocator()));
 
+/// An allocator behind a lock, interrupt handlers can allocate.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Self {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
 
+/// A `GlobalAlloc` that is given the memory region to manage at runtime.
+pub trait HeapAllocator: GlobalAlloc {
+    /// Initializes the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid and that
+    /// the heap is unused. This method must be called only once.
+    unsafe fn init(&self, heap_start: usize, heap_size: usize);
+
+    /// Gives the allocator the `additional` bytes following its heap.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory following the heap is valid
+    /// and unused.
+    unsafe fn extend(&self, additional: usize);
+}
+
+/// Usage of a heap, the sizes are in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    /// Sum of the sizes of the live allocations.
+    pub in_use: usize,
+    pub peak_in_use: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Memory that can still be allocated.
+    pub free: usize,
+    /// `None` if the allocator cannot tell.
+    pub largest_free_block: Option<usize>,
+}
+
+impl HeapStats {
+    /// The part of the free memory that is not in the largest free block.
+    #[must_use]
+    pub fn fragmentation_percent(&self) -> Option<usize> {
+        let largest_free_block = self.largest_free_block?;
+        Some(match self.free {
+            0 => 0,
+            free => 100 - largest_free_block.min(free) * 100 / free,
+        })
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} / {} bytes in use, peak {}",
+            self.in_use, self.heap_size, self.peak_in_use
+        )?;
+        writeln!(f, "{} allocations, {} frees", self.allocations, self.frees)?;
+        write!(f, "{} bytes free", self.free)?;
+        if let (Some(largest_free_block), Some(fragmentation)) =
+            (self.largest_free_block, self.fragmentation_percent())
+        {
+            write!(
+                f,
+                ", largest block {largest_free_block}, fragmentation {fragmentation}%"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An allocator reporting its usage.
+pub trait AllocatorStats {
+    fn heap_stats(&self) -> HeapStats;
+
+    /// Writes the state specific to the allocator, such as its free lists.
+    ///
+    /// # Errors
+    ///
+    /// Fails if writing to `out` fails.
+    fn dump(&self, _out: &mut dyn fmt::Write) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// The counters of `HeapStats` updated on each allocation and free.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UsageCounters {
+    in_use: usize,
+    peak_in_use: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+impl UsageCounters {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            in_use: 0,
+            peak_in_use: 0,
+            allocations: 0,
+            frees: 0,
+        }
+    }
+
+    pub fn allocated(&mut self, layout: Layout) {
+        self.in_use += layout.size();
+        self.peak_in_use = self.peak_in_use.max(self.in_use);
+        self.allocations += 1;
+    }
+
+    pub fn freed(&mut self, layout: Layout) {
+        self.in_use -= layout.size();
+        self.frees += 1;
+    }
+
+    /// Completes the counters with what only the allocator knows.
+    #[must_use]
+    pub fn stats(
+        &self,
+        heap_size: usize,
+        free: usize,
+        largest_free_block: Option<usize>,
+    ) -> HeapStats {
+        HeapStats {
+            heap_size,
+            in_use: self.in_use,
+            peak_in_use: self.peak_in_use,
+            allocations: self.allocations,
+            frees: self.frees,
+            free,
+            largest_free_block,
+        }
+    }
+}
+
+/// Writes the failed layout, the heap usage and the allocator state to `out`.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails.
+pub fn report_alloc_failure(layout: Layout, out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(
+        out,
+        "allocation of {} bytes aligned to {} failed",
+        layout.size(),
+        layout.align()
+    )?;
+    writeln!(out, "heap ({HEAP_ALLOCATOR_NAME}):")?;
+    writeln!(out, "{}", heap_stats())?;
+    HEAP_ALLOCATOR.dump(out)
+}
+
+/// Returns the live allocations of the kernel heap, they are only tracked
+/// with the `alloc-debug` feature.
+#[must_use]
+pub fn live_allocations() -> Option<&'static debug::LiveAllocations> {
+    #[cfg(feature = "alloc-debug")]
+    return Some(HEAP_ALLOCATOR.allocator().live());
+    #[cfg(not(feature = "alloc-debug"))]
+    return None;
+}
+
+/// Returns the usage of the kernel heap.
+#[must_use]
+pub fn heap_stats() -> HeapStats {
+    HEAP_ALLOCATOR.heap_stats()
+}
+
+impl HeapAllocator for LockedHeap {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.lock().init(heap_start, heap_size);
+        }
+    }
+
+    unsafe fn extend(&self, additional: usize) {
+        unsafe {
+            self.lock().extend(additional);
+        }
+    }
+}
+
+/// The global allocator: `A` over the virtual range reserved at `HEAP_START`.
+///
+/// Only `HEAP_SIZE` bytes are mapped at boot. When `A` runs out of memory,
+/// more of the range is mapped and given to it. The pages are mapped up front
+/// rather than on first touch, so that the allocations made while the memory
+/// manager is locked do not fault on them, and an access past the end of the
+/// heap faults.
+pub struct KernelHeap<A> {
+    allocator: A,
+    /// The size of the mapped part of the range.
+    heap_size: IrqMutex<usize>,
+}
+
+impl<A> KernelHeap<A> {
+    pub const fn new(allocator: A) -> Self {
+        Self {
+            allocator,
+            heap_size: IrqMutex::new(0),
+        }
+    }
+
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+}
+
+impl<A: HeapAllocator> KernelHeap<A> {
+    /// Maps the first `HEAP_SIZE` bytes and initializes `A` with them.
+    ///
+    /// # Safety
+    ///
+    /// `memory::MEMORY_MANAGER` must be initialized.
+    /// This method must be called only once.
+    unsafe fn init(&self) -> Result<(), VmallocError> {
+        let mut heap_size = self.heap_size.lock();
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        {
+            let mut memory = memory::MEMORY_MANAGER.lock();
+            let memory = memory.as_mut().ok_or(VmallocError::NotInitialized)?;
+            memory.map_range(heap_start, HEAP_SIZE, heap_flags())?;
+        }
+        unsafe {
+            // SAFETY: the memory was just mapped.
+            self.allocator.init(HEAP_START, HEAP_SIZE);
+        }
+        *heap_size = HEAP_SIZE;
+        Ok(())
+    }
+
+    /// Maps enough of the range after the heap and gives it to `A` to serve
+    /// `layout`. Fails if no frame is left or if the memory manager is locked.
+    fn grow(&self, layout: Layout) -> bool {
+        let mut heap_size = self.heap_size.lock();
+        let needed = layout.size().saturating_add(layout.align());
+        // Growing by a part of the heap keeps the number of extensions low,
+        // and leaves room for the bigger bitmap of the buddy allocator.
+        let mut wanted = align_up(needed.max(HEAP_GROWTH).max(*heap_size / 8), PAGE_SIZE);
+        // Ending the large extensions on a 2 MiB boundary lets the memory
+        // manager map them with huge pages.
+        if wanted >= HUGE_PAGE_SIZE {
+            let heap_end = HEAP_START + *heap_size;
+            wanted = align_up(heap_end + wanted, HUGE_PAGE_SIZE) - heap_end;
+        }
+        let additional = wanted.min(HEAP_MAX_SIZE - *heap_size);
+        if *heap_size == 0 || additional < needed {
+            return false;
+        }
+
+        // The allocation may come from the code holding the lock.
+        let Some(mut memory) = memory::MEMORY_MANAGER.try_lock() else {
+            return false;
+        };
+        let heap_end = VirtAddr::new((HEAP_START + *heap_size) as u64);
+        let mapped = memory
+            .as_mut()
+            .is_some_and(|memory| memory.map_range(heap_end, additional, heap_flags()).is_ok());
+        drop(memory);
+        if !mapped {
+            return false;
+        }
+        unsafe {
+            // SAFETY: the memory was just mapped in the range of the heap.
+            self.allocator.extend(additional);
+        }
+        *heap_size += additional;
+        true
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for KernelHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let ptr = unsafe { self.allocator.alloc(layout) };
+            if !ptr.is_null() || !self.grow(layout) {
+                return ptr;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            self.allocator.dealloc(ptr, layout);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe {
+            // SAFETY: the caller guarantees that the new size is valid for the alignment.
+            Layout::from_size_align_unchecked(new_size, layout.align())
+        };
+        loop {
+            let new_ptr = unsafe { self.allocator.realloc(ptr, layout, new_size) };
+            if !new_ptr.is_null() || !self.grow(new_layout) {
+                return new_ptr;
+            }
+        }
+    }
+}
+
+impl<A: AllocatorStats> AllocatorStats for KernelHeap<A> {
+    fn heap_stats(&self) -> HeapStats {
+        self.allocator.heap_stats()
+    }
+
+    fn dump(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.allocator.dump(out)
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+fn heap_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+/// Moves the block to a new allocation of `new_size` bytes, like the default
+/// `GlobalAlloc::realloc`, for the allocators that cannot resize it in place.
+///
+/// # Safety
+///
+/// Same as `GlobalAlloc::realloc`.
+unsafe fn realloc_by_copy(
+    allocator: &impl GlobalAlloc,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    unsafe {
+        // SAFETY: the caller guarantees that the new size is valid for the alignment.
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = allocator.alloc(new_layout);
+        if !new_ptr.is_null() {
+            // SAFETY: both blocks are valid for the smallest of the sizes.
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            allocator.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
 /// Aligns the given `addr` upwards to alignment `align`.
 fn align_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
@@ -51,54 +474,185 @@ fn align_up(addr: usize, align: usize) -> usize {
 }
 
 /// A faster implementation of `align_up` method.
-fn fast_align_up(addr: usize, align: usize) -> usize {
+const fn fast_align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
 /// # Errors
 ///
-/// Fails if no frame left, `HUGE_PAGE` are in use or the given page is already
-/// mapped to a physical frame.
+/// Fails if no frame left or the given page is already mapped to a physical
+/// frame.
 pub fn init_heap(
     physical_memory_offset: u64,
     memory_map: &'static MemoryMap,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), VmallocError> {
     let phys_mem_offset = VirtAddr::new(physical_memory_offset);
-    let mut mapper = unsafe {
+    unsafe {
         // SAFETY: complete physical memory is mapped to virtual memory
-        // at the provided offset, also it is called once here.
-        memory::init(phys_mem_offset)
-    };
-    let mut frame_allocator = unsafe {
-        // SAFETY: `memory_map` is provided by the boot info.
-        memory::BootInfoFrameAllocator::new(memory_map)
-    };
+        // at the provided offset, `memory_map` is provided by the boot info
+        // and it is called once here.
+        memory::init(phys_mem_offset, memory_map);
+    }
 
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1_u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+    unsafe {
+        // SAFETY: the memory manager is initialized and this function is
+        // called once.
+        HEAP_ALLOCATOR.init()
+    }
+}
+
+#[cfg(test)]
+mod allocator_tests {
+    use alloc::alloc::Layout;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::buddy::BuddyAllocator;
+    use super::bump::BumpAllocator;
+    use super::fixed_size_block::FixedSizeBlockAllocator;
+    use super::linked_list::{BestFit, FirstFit, LinkedListAllocator, NextFit};
+    use super::{
+        heap_stats, report_alloc_failure, AllocatorStats, HeapAllocator, HeapStats, Locked,
     };
+    use crate::test_utils::prop::{check, Layouts, Vecs};
+    use crate::test_utils::Arena;
+    use crate::{assert, assert_eq};
+
+    static ARENA: Arena<{ 16 * 1024 }> = Arena::new();
+
+    /// Checks random sequences of frees (`0`) and allocations (any other op),
+    /// each live block is filled with a byte checked before being freed.
+    fn fuzz_allocator<A: HeapAllocator>(new_allocator: impl Fn() -> A) {
+        let operations = Vecs {
+            element: (
+                0..=3_u8,
+                Layouts {
+                    max_size: 512,
+                    max_align: 64,
+                },
+                0..=63_usize,
+            ),
+            max_len: 64,
+        };
+        check(&operations, |operations| {
+            let allocator = new_allocator();
+            unsafe {
+                // SAFETY: the previous allocator of the arena is dropped.
+                allocator.init(ARENA.start(), ARENA.size());
+            }
+            let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+
+            for (fill, &(op, layout, index)) in (0..=u8::MAX).cycle().zip(operations) {
+                if op == 0 && !live.is_empty() {
+                    let (ptr, layout, fill) = live.swap_remove(index % live.len());
+                    let block = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                    assert!(block.iter().all(|&b| b == fill), "{ptr:p} was overwritten");
+                    unsafe { allocator.dealloc(ptr, layout) };
+                    continue;
+                }
+
+                let ptr = unsafe { allocator.alloc(layout) };
+                if ptr.is_null() {
+                    // Running out of memory is allowed.
+                    continue;
+                }
+                let (start, end) = (ptr as usize, ptr as usize + layout.size());
+                assert_eq!(start % layout.align(), 0);
+                assert!(ARENA.start() <= start && end <= ARENA.end());
+                assert!(live.iter().all(|&(other, other_layout, _)| {
+                    end <= other as usize || other as usize + other_layout.size() <= start
+                }));
+                unsafe { ptr.write_bytes(fill, layout.size()) };
+                live.push((ptr, layout, fill));
+            }
+
+            for (ptr, layout, _) in live {
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+        });
+    }
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    #[test_case]
+    fn test_fuzz_bump_allocator() {
+        fuzz_allocator(|| Locked::new(BumpAllocator::new()));
+    }
+
+    #[test_case]
+    fn test_fuzz_linked_list_allocator() {
+        fuzz_allocator(|| Locked::new(LinkedListAllocator::<FirstFit>::new()));
+        fuzz_allocator(|| Locked::new(LinkedListAllocator::<NextFit>::new()));
+        fuzz_allocator(|| Locked::new(LinkedListAllocator::<BestFit>::new()));
+    }
+
+    #[test_case]
+    fn test_fuzz_fixed_size_block_allocator() {
+        fuzz_allocator(|| Locked::new(FixedSizeBlockAllocator::new()));
+    }
+
+    #[test_case]
+    fn test_fuzz_buddy_allocator() {
+        fuzz_allocator(|| Locked::new(BuddyAllocator::new()));
+    }
+
+    #[test_case]
+    fn test_fragmentation_percent() {
+        let stats = |free, largest_free_block| HeapStats {
+            free,
+            largest_free_block,
+            ..HeapStats::default()
+        };
+        assert_eq!(stats(0, Some(0)).fragmentation_percent(), Some(0));
+        assert_eq!(stats(1000, Some(1000)).fragmentation_percent(), Some(0));
+        assert_eq!(stats(1000, Some(250)).fragmentation_percent(), Some(75));
+        assert_eq!(stats(1000, None).fragmentation_percent(), None);
+    }
+
+    #[test_case]
+    fn test_alloc_failure_report() {
+        let layout = Layout::from_size_align(123, 8).unwrap();
+        let mut report = String::new();
+        report_alloc_failure(layout, &mut report).unwrap();
+
+        assert!(report.starts_with("allocation of 123 bytes aligned to 8 failed\n"));
+        assert!(report.contains("bytes in use"), "{report}");
+    }
+
+    #[test_case]
+    fn test_dump_free_list() {
+        let allocator = Locked::new(LinkedListAllocator::<FirstFit>::new());
         unsafe {
-            mapper
-                .map_to(page, frame, flags, &mut frame_allocator)?
-                .flush();
+            // SAFETY: the previous allocator of the arena is dropped.
+            allocator.init(ARENA.start(), ARENA.size());
         }
+        let mut dump = String::new();
+        allocator.dump(&mut dump).unwrap();
+
+        let region = alloc::format!("  {:#x}..{:#x} ", ARENA.start(), ARENA.end());
+        assert!(dump.starts_with("1 free regions"), "{dump}");
+        assert!(dump.contains(&region), "{dump}");
     }
 
-    unsafe {
-        // SAFETY: at this point only this method
-        // has access to the static variable.
-        HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    #[test_case]
+    fn test_no_growth_under_memory_lock() {
+        let size = heap_stats().free + 1024 * 1024;
+        let mut buffer: Vec<u8> = Vec::new();
+        {
+            let _memory = crate::memory::MEMORY_MANAGER.lock();
+            assert!(buffer.try_reserve_exact(size).is_err());
+        }
+        assert!(buffer.try_reserve_exact(size).is_ok());
     }
 
-    Ok(())
+    #[test_case]
+    fn test_past_heap_end_not_mapped_on_fault() {
+        use x86_64::structures::idt::PageFaultErrorCode;
+        use x86_64::VirtAddr;
+
+        let heap_end = VirtAddr::new((super::HEAP_START + heap_stats().heap_size) as u64);
+        assert!(!crate::memory::handle_page_fault(
+            heap_end,
+            PageFaultErrorCode::empty()
+        ));
+        assert_eq!(crate::memory::page_walk(heap_end).unwrap().mapping, None);
+    }
 }
@@ -0,0 +1,359 @@
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::allocator::fast_align_up;
+use crate::memory;
+
+/// Size of a slab, one frame.
+pub const SLAB_SIZE: usize = 4096;
+
+/// Kept at the start of each slab page.
+struct SlabHeader {
+    previous: Option<NonNull<SlabHeader>>,
+    next: Option<NonNull<SlabHeader>>,
+    free: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+/// Stored in the slot of each free object.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// A cache of `T` objects carved from page sized slabs taken from
+/// `memory::MEMORY_MANAGER`.
+///
+/// Each object is built by the constructor when it is allocated and given to
+/// the destructor, then dropped, when it is freed. A slab goes back to the
+/// frame allocator as soon as its last object is freed.
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    destructor: Option<fn(&mut T)>,
+    /// Slabs with at least one free object.
+    partial: Option<NonNull<SlabHeader>>,
+    /// Slabs without free objects.
+    full: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    in_use: usize,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: the slabs are only reached through the cache.
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Offset of the first object in a slab.
+    const FIRST_OBJECT: usize = fast_align_up(mem::size_of::<SlabHeader>(), Self::ALIGN);
+    const ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<FreeObject>() {
+        mem::align_of::<T>()
+    } else {
+        mem::align_of::<FreeObject>()
+    };
+    /// Distance between two objects.
+    const STRIDE: usize = fast_align_up(
+        if mem::size_of::<T>() > mem::size_of::<FreeObject>() {
+            mem::size_of::<T>()
+        } else {
+            mem::size_of::<FreeObject>()
+        },
+        Self::ALIGN,
+    );
+    pub const OBJECTS_PER_SLAB: usize = {
+        assert!(
+            Self::FIRST_OBJECT + Self::STRIDE <= SLAB_SIZE,
+            "the objects do not fit in a slab"
+        );
+        (SLAB_SIZE - Self::FIRST_OBJECT) / Self::STRIDE
+    };
+
+    #[must_use]
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        Self {
+            name,
+            constructor,
+            destructor: None,
+            partial: None,
+            full: None,
+            slabs: 0,
+            in_use: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Like `new`, with a destructor called on each freed object before it is dropped.
+    #[must_use]
+    pub const fn with_destructor(
+        name: &'static str,
+        constructor: fn() -> T,
+        destructor: fn(&mut T),
+    ) -> Self {
+        let mut cache = Self::new(name, constructor);
+        cache.destructor = Some(destructor);
+        cache
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Number of slabs currently held by the cache.
+    #[must_use]
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// Number of allocated objects.
+    #[must_use]
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// Returns a new object built by the constructor,
+    /// or `None` if no frame is left for a new slab.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a slab of the partial list has no free object.
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        if self.partial.is_none() {
+            let slab = Self::new_slab()?;
+            self.slabs += 1;
+            unsafe {
+                // SAFETY: the slab is not in a list.
+                Self::push(&mut self.partial, slab);
+            }
+        }
+
+        let mut slab = self.partial?;
+        let object = unsafe {
+            // SAFETY: the slabs of the partial list are valid and have a free object.
+            let header = slab.as_mut();
+            let object = header
+                .free
+                .take()
+                .expect("partial slab without free object");
+            header.free = object.as_ref().next;
+            header.in_use += 1;
+            if header.free.is_none() {
+                Self::unlink(&mut self.partial, slab);
+                Self::push(&mut self.full, slab);
+            }
+            object.cast::<T>()
+        };
+
+        unsafe {
+            // SAFETY: the slot is free, aligned and big enough for a `T`.
+            object.as_ptr().write((self.constructor)());
+        }
+        self.in_use += 1;
+        Some(object)
+    }
+
+    /// Destroys the object and gives its slot back to its slab.
+    ///
+    /// # Safety
+    ///
+    /// The object must come from `alloc` on this cache and must not be used afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the object pointer is not in a slab.
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        unsafe {
+            // SAFETY: the caller guarantees that the object is valid and unused.
+            if let Some(destructor) = self.destructor {
+                destructor(&mut *object.as_ptr());
+            }
+            ptr::drop_in_place(object.as_ptr());
+        }
+        self.in_use -= 1;
+
+        let slab_addr = object.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let mut slab = NonNull::new(slab_addr as *mut SlabHeader).unwrap();
+        unsafe {
+            // SAFETY: the slabs are page aligned and start with their header.
+            let header = slab.as_mut();
+            let was_full = header.free.is_none();
+            let free_object = object.cast::<FreeObject>();
+            free_object.as_ptr().write(FreeObject { next: header.free });
+            header.free = Some(free_object);
+            header.in_use -= 1;
+
+            if was_full {
+                Self::unlink(&mut self.full, slab);
+                Self::push(&mut self.partial, slab);
+            }
+            if header.in_use == 0 {
+                Self::unlink(&mut self.partial, slab);
+                Self::release_slab(slab);
+                self.slabs -= 1;
+            }
+        }
+    }
+
+    /// Takes a frame and threads all of its slots on the free list.
+    fn new_slab() -> Option<NonNull<SlabHeader>> {
+        let frame: PhysFrame = memory::MEMORY_MANAGER
+            .lock()
+            .as_mut()?
+            .frame_allocator()
+            .allocate_frame()?;
+        let start = memory::phys_to_virt(frame.start_address());
+
+        let mut free = None;
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot: *mut FreeObject =
+                (start + Self::FIRST_OBJECT + index * Self::STRIDE).as_mut_ptr();
+            unsafe {
+                // SAFETY: the slot is in the new frame, after the header.
+                slot.write(FreeObject { next: free });
+            }
+            free = NonNull::new(slot);
+        }
+
+        let header = SlabHeader {
+            previous: None,
+            next: None,
+            free,
+            in_use: 0,
+        };
+        let header_ptr: *mut SlabHeader = start.as_mut_ptr();
+        unsafe {
+            // SAFETY: the start of the frame is reserved for the header.
+            header_ptr.write(header);
+        }
+        NonNull::new(header_ptr)
+    }
+
+    unsafe fn release_slab(slab: NonNull<SlabHeader>) {
+        let start = VirtAddr::from_ptr(slab.as_ptr());
+        let frame = PhysFrame::containing_address(memory::virt_to_phys(start));
+        if let Some(memory) = memory::MEMORY_MANAGER.lock().as_mut() {
+            unsafe {
+                // SAFETY: the slab has no object left.
+                memory.frame_allocator().deallocate_frame(frame);
+            }
+        }
+    }
+
+    unsafe fn push(list: &mut Option<NonNull<SlabHeader>>, mut slab: NonNull<SlabHeader>) {
+        unsafe {
+            // SAFETY: the caller guarantees that the slab is valid and not in a list.
+            let header = slab.as_mut();
+            header.previous = None;
+            header.next = *list;
+            if let Some(mut next) = *list {
+                next.as_mut().previous = Some(slab);
+            }
+        }
+        *list = Some(slab);
+    }
+
+    unsafe fn unlink(list: &mut Option<NonNull<SlabHeader>>, mut slab: NonNull<SlabHeader>) {
+        unsafe {
+            // SAFETY: the caller guarantees that the slab is in the list.
+            let header = slab.as_mut();
+            match header.previous {
+                Some(mut previous) => previous.as_mut().next = header.next,
+                None => *list = header.next,
+            }
+            if let Some(mut next) = header.next {
+                next.as_mut().previous = header.previous;
+            }
+            header.previous = None;
+            header.next = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod slab_tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{assert, assert_eq};
+
+    static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Object {
+        value: u64,
+        _padding: [u8; 200],
+    }
+
+    fn new_object() -> Object {
+        Object {
+            value: 42,
+            _padding: [0; 200],
+        }
+    }
+
+    fn destroy_object(object: &mut Object) {
+        assert_eq!(object.value, 42);
+        DESTROYED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn test_constructor_and_destructor() {
+        let mut cache = SlabCache::with_destructor("object", new_object, destroy_object);
+        let destroyed = DESTROYED.load(Ordering::Relaxed);
+
+        let object = cache.alloc().unwrap();
+        assert_eq!(unsafe { object.as_ref() }.value, 42);
+        assert_eq!(cache.in_use(), 1);
+
+        unsafe { cache.free(object) };
+        assert_eq!(DESTROYED.load(Ordering::Relaxed), destroyed + 1);
+        assert_eq!(cache.in_use(), 0);
+    }
+
+    #[test_case]
+    fn test_free_slot_is_reused() {
+        let mut cache = SlabCache::new("object", new_object);
+        let first = cache.alloc().unwrap();
+        let second = cache.alloc().unwrap();
+        assert!(first != second);
+        assert_eq!(second.as_ptr() as usize % mem::align_of::<Object>(), 0);
+
+        unsafe { cache.free(first) };
+        let third = cache.alloc().unwrap();
+        assert_eq!(first, third);
+        assert_eq!(cache.slabs(), 1);
+
+        unsafe {
+            cache.free(second);
+            cache.free(third);
+        }
+    }
+
+    #[test_case]
+    fn test_empty_slabs_are_released() {
+        let mut cache = SlabCache::new("object", new_object);
+        let count = 3 * SlabCache::<Object>::OBJECTS_PER_SLAB;
+
+        let objects: Vec<_> = (0..count).map(|_| cache.alloc().unwrap()).collect();
+        assert_eq!(cache.slabs(), 3);
+        assert!(objects
+            .iter()
+            .all(|object| (object.as_ptr() as usize & (SLAB_SIZE - 1))
+                >= mem::size_of::<SlabHeader>()));
+
+        // Freeing every other object leaves every slab partially used.
+        for object in objects.iter().step_by(2) {
+            unsafe { cache.free(*object) };
+        }
+        assert_eq!(cache.slabs(), 3);
+
+        for object in objects.iter().skip(1).step_by(2) {
+            unsafe { cache.free(*object) };
+        }
+        assert_eq!(cache.slabs(), 0);
+        assert_eq!(cache.in_use(), 0);
+    }
+}
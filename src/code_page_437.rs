@@ -185,3 +185,30 @@ lazy_static! {
         map
     };
 }
+
+#[cfg(test)]
+mod code_page_437_bench {
+    use core::hint::black_box;
+
+    use super::*;
+    use crate::bench_case;
+
+    bench_case! {
+        /// Worst case of the linear search: the last inserted character.
+        fn bench_map_lookup_last(bencher) {
+            bencher.measure(|| UTF_8_TO_CODE_PAGE_437_MAP.get(black_box(&'Ÿ')).copied());
+        }
+    }
+
+    bench_case! {
+        fn bench_map_lookup_pangramme(bencher) {
+            bencher.set_iterations(10);
+            bencher.measure(|| {
+                black_box(PANGRAMME)
+                    .chars()
+                    .filter_map(|c| UTF_8_TO_CODE_PAGE_437_MAP.get(&c))
+                    .count()
+            });
+        }
+    }
+}
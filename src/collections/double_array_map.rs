@@ -102,6 +102,7 @@ where
 #[cfg(test)]
 mod double_array_map_test {
     use crate::assert_eq;
+    use crate::test_utils::prop::{check, Vecs};
 
     use super::DoubleArrayMap as Dam;
     use super::*;
@@ -141,4 +142,35 @@ mod double_array_map_test {
             Ok::<Option<&str>, DoubleArrayMapError>(Some("un"))
         );
     }
+
+    #[test_case]
+    fn test_insert_against_model() {
+        let inserts = Vecs {
+            element: (0..=7_u8, 0..=255_u8),
+            max_len: 10,
+        };
+        check(&inserts, |inserts| {
+            let mut map: Dam<5, u8, u8> = Dam::new();
+            let mut model: [Option<u8>; 8] = [None; 8];
+
+            for &(key, value) in inserts {
+                let len = model.iter().flatten().count();
+                let previous = model[usize::from(key)];
+                if previous.is_some() || len < 5 {
+                    assert_eq!(map.insert(key, value), Ok(previous));
+                    model[usize::from(key)] = Some(value);
+                } else {
+                    assert_eq!(
+                        map.insert(key, value),
+                        Err(DoubleArrayMapError::ExceedCapacity)
+                    );
+                }
+                assert_eq!(map.len(), model.iter().flatten().count());
+            }
+
+            for key in 0..8 {
+                assert_eq!(map.get(&key), model[usize::from(key)].as_ref());
+            }
+        });
+    }
 }
@@ -0,0 +1,22 @@
+use crate::allocator;
+use crate::println;
+
+/// The allocations listed, the most recent ones.
+const MAX_LISTED: usize = 16;
+
+/// Prints the live allocations of the kernel heap.
+pub fn leaks(_command: &mut dyn Iterator<Item = &str>) {
+    let Some(live) = allocator::live_allocations() else {
+        println!("leaks: build with the alloc-debug feature to track allocations");
+        return;
+    };
+
+    println!("{} live allocations", live.count());
+    let mut listed = 0;
+    live.for_each_since(0, |allocation| {
+        if listed < MAX_LISTED {
+            println!("  {allocation}");
+            listed += 1;
+        }
+    });
+}
@@ -0,0 +1,15 @@
+use x86_64::instructions::interrupts;
+
+use crate::memory;
+use crate::println;
+use crate::serial::SERIAL1;
+
+/// Writes the present mappings to the serial port, the list does not fit
+/// on the screen.
+pub fn mappings(_command: &mut dyn Iterator<Item = &str>) {
+    let result = interrupts::without_interrupts(|| memory::dump_mappings(&mut *SERIAL1.lock()));
+    match result {
+        Ok(()) => println!("mappings written to the serial port"),
+        Err(_) => println!("mappings: writing to the serial port failed"),
+    }
+}
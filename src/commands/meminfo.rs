@@ -0,0 +1,24 @@
+use crate::allocator::{self, HEAP_ALLOCATOR_NAME};
+use crate::memory;
+use crate::println;
+
+/// Prints the heap usage and the frame counts.
+pub fn meminfo(_command: &mut dyn Iterator<Item = &str>) {
+    let heap_stats = allocator::heap_stats();
+    let frame_stats = memory::MEMORY_MANAGER
+        .lock()
+        .as_ref()
+        .map(memory::MemoryManager::frame_stats);
+
+    println!("heap ({HEAP_ALLOCATOR_NAME}):");
+    println!("{heap_stats}");
+    match frame_stats {
+        Some(stats) => println!(
+            "frames: {} used, {} free, {} total",
+            stats.used,
+            stats.free(),
+            stats.total
+        ),
+        None => println!("frames: not initialized"),
+    }
+}
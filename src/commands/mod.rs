@@ -1 +1,6 @@
 pub mod echo;
+pub mod leaks;
+pub mod mappings;
+pub mod meminfo;
+pub mod pagewalk;
+pub mod vmmap;
@@ -0,0 +1,25 @@
+use x86_64::VirtAddr;
+
+use crate::memory;
+use crate::println;
+
+/// Prints the page table entries that translate the given address.
+pub fn pagewalk(command: &mut dyn Iterator<Item = &str>) {
+    let Some(argument) = command.next() else {
+        println!("usage: pagewalk <address>");
+        return;
+    };
+    let digits = argument.trim_start_matches("0x");
+    let Some(addr) = u64::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|addr| VirtAddr::try_new(addr).ok())
+    else {
+        println!("pagewalk: '{argument}' is not a canonical hexadecimal address");
+        return;
+    };
+
+    match memory::page_walk(addr) {
+        Some(walk) => println!("{walk}"),
+        None => println!("pagewalk: memory not initialized"),
+    }
+}
@@ -0,0 +1,17 @@
+use crate::memory;
+use crate::println;
+
+/// Prints the regions of the vmalloc window.
+pub fn vmmap(_command: &mut dyn Iterator<Item = &str>) {
+    let memory = memory::MEMORY_MANAGER.lock();
+    let Some(memory) = memory.as_ref() else {
+        println!("vmmap: memory not initialized");
+        return;
+    };
+
+    let regions = memory.regions();
+    println!("{} regions", regions.iter().count());
+    for region in regions.iter() {
+        println!("  {region}");
+    }
+}
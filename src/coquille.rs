@@ -2,6 +2,7 @@ use lazy_static::lazy_static;
 use pc_keyboard::DecodedKey;
 use spin::Mutex;
 
+use crate::commands;
 use crate::stack_string::StackString;
 use crate::vga_buffer::VGA_BUFFER_WRITER;
 use crate::{print, println};
@@ -48,8 +49,19 @@ fn exec_command(command: &[char]) {
 
     let splited: &mut dyn Iterator<Item = &str> = &mut command.split_whitespace();
 
-    for part in splited {
-        println!("'{part}'");
+    match splited.next() {
+        Some("leaks") => commands::leaks::leaks(splited),
+        Some("mappings") => commands::mappings::mappings(splited),
+        Some("meminfo") => commands::meminfo::meminfo(splited),
+        Some("pagewalk") => commands::pagewalk::pagewalk(splited),
+        Some("vmmap") => commands::vmmap::vmmap(splited),
+        Some(name) => {
+            println!("'{name}'");
+            for part in splited {
+                println!("'{part}'");
+            }
+        }
+        None => {}
     }
 }
 
@@ -0,0 +1,127 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, __get_cpuid_max};
+use core::fmt;
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// The protection features of the processor, from `cpuid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    /// Supervisor mode execution prevention.
+    pub smep: bool,
+    /// Supervisor mode access prevention.
+    pub smap: bool,
+    /// User mode instruction prevention.
+    pub umip: bool,
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let features = [
+            ("SMEP", self.smep),
+            ("SMAP", self.smap),
+            ("UMIP", self.umip),
+        ];
+        let mut first = true;
+        for (name, _) in features.iter().filter(|(_, supported)| *supported) {
+            if !first {
+                write!(f, " ")?;
+            }
+            write!(f, "{name}")?;
+            first = false;
+        }
+        if first {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the structured extended features, leaf 7.
+#[must_use]
+pub fn features() -> Features {
+    const LEAF: u32 = 7;
+    const SMEP: u32 = 1 << 7;
+    const SMAP: u32 = 1 << 20;
+    const UMIP: u32 = 1 << 2;
+
+    let (max_leaf, _) = __get_cpuid_max(0);
+    if max_leaf < LEAF {
+        return Features::default();
+    }
+    let leaf = __cpuid_count(LEAF, 0);
+    Features {
+        smep: leaf.ebx & SMEP != 0,
+        smap: leaf.ebx & SMAP != 0,
+        umip: leaf.ecx & UMIP != 0,
+    }
+}
+
+/// Whether the processor can map 1 GiB pages.
+#[must_use]
+pub fn supports_1gib_pages() -> bool {
+    const LEAF: u32 = 0x8000_0001;
+    const PDPE1GB: u32 = 1 << 26;
+
+    let (max_leaf, _) = __get_cpuid_max(0x8000_0000);
+    max_leaf >= LEAF && __cpuid(LEAF).edx & PDPE1GB != 0
+}
+
+/// Turns on the protections against the kernel executing or accessing user
+/// pages, and against user code reading the descriptor tables, when the
+/// processor supports them.
+pub fn init() {
+    use crate::println;
+
+    let features = features();
+    println!("Enabling CPU protections: {features}.");
+    let mut flags = Cr4Flags::empty();
+    flags.set(
+        Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+        features.smep,
+    );
+    flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, features.smap);
+    flags.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, features.umip);
+    unsafe {
+        // SAFETY: the features are supported and the kernel only touches user
+        // pages through `copy_from_user` and `copy_to_user`.
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+}
+
+/// Whether the kernel must use `stac` to access user pages.
+#[must_use]
+pub fn smap_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+}
+
+#[cfg(test)]
+mod cpu_tests {
+    use alloc::format;
+
+    use super::*;
+    use crate::assert_eq;
+
+    #[test_case]
+    fn test_supported_features_enabled() {
+        let features = features();
+        let cr4 = Cr4::read();
+        assert_eq!(
+            cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+            features.smep
+        );
+        assert_eq!(smap_enabled(), features.smap);
+        assert_eq!(
+            cr4.contains(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION),
+            features.umip
+        );
+    }
+
+    #[test_case]
+    fn test_display_features() {
+        let mut features = Features::default();
+        assert_eq!(format!("{features}"), "none");
+        features.smap = true;
+        features.umip = true;
+        assert_eq!(format!("{features}"), "SMAP UMIP");
+    }
+}
@@ -3,34 +3,69 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::MEMORY_MANAGER;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const STACK_SIZE: usize = 4096 * 5;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            stack_start + STACK_SIZE // The stack end, from top to bottom.
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            interrupt_stack("double fault stack");
+        // The stack of the interrupts of ring 3 code.
+        tss.privilege_stack_table[0] = interrupt_stack("privilege stack");
         tss
     };
-
     static ref GDT: (GlobalDescriptorTable, Selector) = {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selector { code_selector, tss_selector })
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        (
+            gdt,
+            Selector {
+                code,
+                tss,
+                user_data,
+                user_code,
+            },
+        )
     };
 }
 
+/// Maps a stack with a guard page below it, returns its end.
+fn interrupt_stack(name: &'static str) -> VirtAddr {
+    MEMORY_MANAGER
+        .lock()
+        .as_mut()
+        .expect("memory not initialized")
+        .alloc_stack(name, STACK_SIZE)
+        .expect("no memory left for the interrupt stacks")
+}
+
 struct Selector {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+    code: SegmentSelector,
+    tss: SegmentSelector,
+    user_data: SegmentSelector,
+    user_code: SegmentSelector,
+}
+
+/// The ring 3 code and data segments, for the `iretq` to user code.
+#[must_use]
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code, GDT.1.user_data)
 }
 
+/// Loads the GDT and the TSS, whose interrupt stacks are mapped with guard
+/// pages.
+///
+/// # Panics
+///
+/// Panics if called before `memory::init` or if the interrupt stacks cannot
+/// be mapped.
 pub fn init() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
@@ -41,7 +76,7 @@ pub fn init() {
     GDT.0.load();
     unsafe {
         // SAFETY: the selectors are valid.
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(GDT.1.code);
+        load_tss(GDT.1.tss);
     }
 }
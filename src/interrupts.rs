@@ -8,6 +8,7 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 use crate::coquille::COQUILLE;
 use crate::gdt;
 use crate::hlt_loop;
+use crate::memory;
 use crate::println;
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -38,6 +39,16 @@ lazy_static! {
         idt
     };
 
+    /// Loaded while the memory is set up, before the interrupt stacks are
+    /// mapped: the double fault runs on the faulting stack.
+    static ref EARLY_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt
+    };
+
 }
 
 #[derive(Debug, Clone)]
@@ -57,6 +68,13 @@ impl InterruptIndex {
     }
 }
 
+/// Reports the faults raised while the memory is set up.
+pub fn init_early_idt() {
+    EARLY_IDT.load();
+}
+
+/// Must be called after `gdt::init`, the double fault handler runs on a stack
+/// of the TSS.
 pub fn init_idt() {
     println!("Initializing idt.");
     IDT.load();
@@ -101,6 +119,13 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // A kernel stack overflow faults again when the processor pushes the
+    // frame of the page fault on the full stack.
+    if let Some(stack) = memory::overflowed_stack(Cr2::read()) {
+        panic!("EXCEPTION: DOUBLE FAULT\nstack overflow in {stack}\n{stack_frame:#?}");
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -110,9 +135,19 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    if memory::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    if let Some(stack) = memory::overflowed_stack(Cr2::read()) {
+        panic!("EXCEPTION: PAGE FAULT\nstack overflow in {stack}\n{stack_frame:#?}");
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
+    if let Some(walk) = memory::page_walk(Cr2::read()) {
+        println!("{walk}");
+    }
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
@@ -0,0 +1,101 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spin lock held with the interrupts disabled, an interrupt handler never
+/// spins on it while the code it interrupted holds it.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enabled,
+        }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let Some(guard) = self.inner.try_lock() else {
+            if enabled {
+                interrupts::enable();
+            }
+            return None;
+        };
+        Some(IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            enabled,
+        })
+    }
+}
+
+/// Releases the lock, then enables the interrupts if they were enabled
+/// before it was taken.
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    enabled: bool,
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            // SAFETY: the guard is not used after this.
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod irq_mutex_tests {
+    use super::*;
+    use crate::{assert, assert_eq};
+
+    #[test_case]
+    fn test_interrupts_disabled_while_held() {
+        let first = IrqMutex::new(1);
+        let second = IrqMutex::new(2);
+        assert!(interrupts::are_enabled());
+        {
+            let mut guard = first.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+            assert!(first.try_lock().is_none());
+            // The nested lock keeps them disabled when released.
+            assert_eq!(*second.try_lock().unwrap(), 2);
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*first.lock(), 2);
+    }
+}
@@ -1,5 +1,6 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![cfg_attr(test, no_main)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_utils::test_runner)]
@@ -12,8 +13,10 @@ pub mod code_page_437;
 pub mod collections;
 pub mod commands;
 pub mod coquille;
+pub mod cpu;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq_mutex;
 pub mod memory;
 pub mod serial;
 pub mod stack_string;
@@ -21,6 +24,8 @@ pub mod vga_buffer;
 
 pub mod test_utils;
 
+use core::alloc::Layout;
+
 use bootloader::BootInfo;
 use x86_64::instructions::port::Port;
 
@@ -45,18 +50,30 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
 /// # Panics
 ///
-/// Fails if no frame left, `HUGE_PAGE` are in use or the given page is already
-/// mapped to a physical frame.
+/// Fails if no frame left or the given page is already mapped to a physical
+/// frame.
 pub fn init(boot_info: &'static BootInfo) {
-    interrupts::init_idt();
+    // Loaded first so that a fault while setting up the memory is reported.
+    interrupts::init_early_idt();
+    allocator::init_heap(boot_info.physical_memory_offset, &boot_info.memory_map)
+        .expect("heap initalization failed");
+    // The interrupt stacks of the TSS are mapped by the memory manager.
     gdt::init();
+    cpu::init();
+    interrupts::init_idt();
     unsafe {
         // SAFETY: the ports are not used.
         interrupts::PICS.lock().initialize();
     };
     x86_64::instructions::interrupts::enable();
-    allocator::init_heap(boot_info.physical_memory_offset, &boot_info.memory_map)
-        .expect("heap initalization failed");
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = allocator::report_alloc_failure(layout, &mut *serial::SERIAL1.lock());
+    });
+    panic!("allocation error: {layout:?}");
 }
 
 pub fn hlt_loop() -> ! {
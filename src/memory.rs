@@ -1,75 +0,0 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
-};
-
-/// A `FrameAllocator` that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-impl BootInfoFrameAllocator {
-    /// Create a `FrameAllocator` from the passed memory map.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are
-    /// marked as `USABLE` in it are really unused.
-    #[must_use]
-    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-    /// Returns an iterator over the usable frame specified in the memor map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        self.memory_map
-            .iter()
-            // Filter the usable memory region.
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            // Map each region to it's address range.
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            // Tranform to an iterator of frame start addresses.
-            .flat_map(|r| r.step_by(4096))
-            // Create `PhysFrame` types from the start addresses.
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-unsafe fn active_level_4_table_mut(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    unsafe {
-        // SAFETY: the pointer is valid.
-        &mut *page_table_ptr
-    }
-}
-
-/// Initialize a new `OffsetPageTable`.
-///
-/// # Safety
-///
-/// This function is unsage because the caller must guarantee that the
-/// complete physical memory is mapped to virtual memory at the passed
-/// `physical_memory_offset`. Also, this function must be only called once
-/// to avoid aliasing `&mut` references (which is undefined behavior).
-#[must_use]
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = active_level_4_table_mut(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
-}
@@ -0,0 +1,595 @@
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MapperFlush, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::walk::entry_size;
+use super::{
+    phys_to_virt, pop_frame, push_frame, usable_range, BootInfoFrameAllocator, MemoryManager,
+};
+
+/// A page size with its own stack of deallocated frames.
+pub(super) trait RecycledSize: PageSize {
+    fn recycled(allocator: &mut BootInfoFrameAllocator) -> &mut Option<PhysFrame<Self>>;
+}
+
+impl RecycledSize for Size4KiB {
+    fn recycled(allocator: &mut BootInfoFrameAllocator) -> &mut Option<PhysFrame<Self>> {
+        &mut allocator.recycled
+    }
+}
+
+impl RecycledSize for Size2MiB {
+    fn recycled(allocator: &mut BootInfoFrameAllocator) -> &mut Option<PhysFrame<Self>> {
+        &mut allocator.recycled_2mib
+    }
+}
+
+impl RecycledSize for Size1GiB {
+    fn recycled(allocator: &mut BootInfoFrameAllocator) -> &mut Option<PhysFrame<Self>> {
+        &mut allocator.recycled_1gib
+    }
+}
+
+/// A huge page size, made of 512 pages of the next smaller size.
+pub(super) trait HugeSize: RecycledSize {
+    type Smaller: RecycledSize;
+}
+
+impl HugeSize for Size2MiB {
+    type Smaller = Size4KiB;
+}
+
+impl HugeSize for Size1GiB {
+    type Smaller = Size2MiB;
+}
+
+impl BootInfoFrameAllocator {
+    /// Takes a deallocated frame of size `S`, or else `S::SIZE` bytes of
+    /// frames aligned on `S::SIZE` from the first usable region where they
+    /// fit, from the one new frames are taken from.
+    ///
+    /// The frames skipped on the way are recycled, nothing changes if no
+    /// region is big enough.
+    fn allocate_aligned<S: HugeSize>(&mut self) -> Option<PhysFrame<S>> {
+        let frame = match unsafe {
+            // SAFETY: the stack only holds deallocated frames.
+            pop_frame(S::recycled(self))
+        } {
+            Some(frame) => frame,
+            None => self.allocate_new()?,
+        };
+        self.used += frame_count(S::SIZE);
+        Some(frame)
+    }
+
+    /// Takes `S::SIZE` bytes of frames never allocated, see `allocate_aligned`.
+    fn allocate_new<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let mut next = self.next;
+        for (index, region) in self.memory_map.iter().enumerate().skip(self.region) {
+            if let Some(range) = usable_range(region) {
+                let start = range
+                    .start
+                    .start_address()
+                    .as_u64()
+                    .max(next)
+                    .next_multiple_of(S::SIZE);
+                if start + S::SIZE <= range.end.start_address().as_u64() {
+                    self.recycle_until(index, start);
+                    self.region = index;
+                    self.next = start + S::SIZE;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+            }
+            next = 0;
+        }
+        None
+    }
+
+    /// Recycles the frames never allocated before `end`, in the region `last`.
+    fn recycle_until(&mut self, last: usize, end: u64) {
+        for index in self.region..=last {
+            let Some(range) = usable_range(&self.memory_map[index]) else {
+                continue;
+            };
+            let mut start = range.start.start_address().as_u64();
+            if index == self.region {
+                start = start.max(self.next);
+            }
+            let end = if index == last {
+                end
+            } else {
+                range.end.start_address().as_u64()
+            };
+            unsafe {
+                // SAFETY: the frames were never allocated.
+                self.recycle_range(start, end);
+            }
+        }
+    }
+
+    /// Puts the frames of `start..end` on the stacks of deallocated frames,
+    /// as the largest aligned frames that fit.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be unused.
+    unsafe fn recycle_range(&mut self, start: u64, end: u64) {
+        let mut addr = start;
+        while addr < end {
+            let fits = |size: u64| addr.is_multiple_of(size) && end - addr >= size;
+            let start = PhysAddr::new(addr);
+            unsafe {
+                // SAFETY: the caller guarantees that the frames are unused.
+                if fits(Size1GiB::SIZE) {
+                    push_frame(
+                        &mut self.recycled_1gib,
+                        PhysFrame::containing_address(start),
+                    );
+                    addr += Size1GiB::SIZE;
+                } else if fits(Size2MiB::SIZE) {
+                    push_frame(
+                        &mut self.recycled_2mib,
+                        PhysFrame::containing_address(start),
+                    );
+                    addr += Size2MiB::SIZE;
+                } else {
+                    push_frame(&mut self.recycled, PhysFrame::containing_address(start));
+                    addr += Size4KiB::SIZE;
+                }
+            }
+        }
+    }
+
+    /// Splits a deallocated frame of size `S` into frames of the next smaller
+    /// size, false if there is none.
+    pub(super) fn split_recycled<S: HugeSize>(&mut self) -> bool {
+        let Some(frame) = (unsafe {
+            // SAFETY: the stack only holds deallocated frames.
+            pop_frame(S::recycled(self))
+        }) else {
+            return false;
+        };
+        let first = PhysFrame::<S::Smaller>::containing_address(frame.start_address());
+        for child in PhysFrame::range(first, first + S::SIZE / S::Smaller::SIZE) {
+            unsafe {
+                // SAFETY: the frame was deallocated.
+                push_frame(S::Smaller::recycled(self), child);
+            }
+        }
+        true
+    }
+
+    /// Gives back the frames of `start..start + size`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must be unused.
+    pub(super) unsafe fn deallocate_range(&mut self, start: PhysAddr, size: u64) {
+        unsafe {
+            // SAFETY: the caller guarantees that the frames are unused.
+            self.recycle_range(start.as_u64(), start.as_u64() + size);
+        }
+        self.used -= frame_count(size);
+    }
+}
+
+/// The number of 4 KiB frames in `size` bytes.
+fn frame_count(size: u64) -> usize {
+    // A page holds at most 2^18 frames.
+    #[allow(clippy::cast_possible_truncation)]
+    let count = (size / Size4KiB::SIZE) as usize;
+    count
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_aligned().or_else(|| {
+            self.split_recycled::<Size1GiB>()
+                .then(|| self.allocate_aligned())
+                .flatten()
+        })
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_aligned()
+    }
+}
+
+/// Why a page of a range cannot be updated on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PageError {
+    NotMapped,
+    /// No frame is left for the table of a huge page to split.
+    NoFrameToSplit,
+}
+
+impl From<PageError> for UnmapError {
+    fn from(error: PageError) -> Self {
+        match error {
+            PageError::NotMapped => Self::PageNotMapped,
+            PageError::NoFrameToSplit => Self::ParentEntryHugePage,
+        }
+    }
+}
+
+impl From<PageError> for FlagUpdateError {
+    fn from(error: PageError) -> Self {
+        match error {
+            PageError::NotMapped => Self::PageNotMapped,
+            PageError::NoFrameToSplit => Self::ParentEntryHugePage,
+        }
+    }
+}
+
+/// The error of a huge page mapping, with the frame seen as a 4 KiB one.
+fn small_error<S: PageSize>(error: &MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+impl MemoryManager {
+    /// The size of the largest page supported at `addr`, a virtual or physical
+    /// address, that fits in `remaining` bytes.
+    pub(super) fn largest_page(&self, addr: u64, remaining: u64) -> u64 {
+        [Size1GiB::SIZE, Size2MiB::SIZE]
+            .into_iter()
+            .filter(|&size| size <= self.max_page_size)
+            .find(|&size| addr.is_multiple_of(size) && remaining >= size)
+            .unwrap_or(Size4KiB::SIZE)
+    }
+
+    /// Maps new frames at `addr` with the largest page that fits in
+    /// `remaining` bytes, or a smaller one if no aligned frames are left.
+    /// Returns the size of the page.
+    pub(super) fn map_new_page(
+        &mut self,
+        addr: VirtAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        let largest = self.largest_page(addr.as_u64(), remaining);
+        if largest >= Size1GiB::SIZE {
+            if let Some(result) = self.map_new_huge_page::<Size1GiB>(addr, flags) {
+                return result;
+            }
+        }
+        if largest >= Size2MiB::SIZE {
+            if let Some(result) = self.map_new_huge_page::<Size2MiB>(addr, flags) {
+                return result;
+            }
+        }
+        self.map_page(Page::containing_address(addr), flags)
+            .map(|()| Size4KiB::SIZE)
+    }
+
+    /// Maps a page of size `S` at `addr` to new frames, `None` if no aligned
+    /// frames are left.
+    fn map_new_huge_page<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Option<Result<u64, MapToError<Size4KiB>>>
+    where
+        BootInfoFrameAllocator: FrameAllocator<S>,
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let frame = FrameAllocator::<S>::allocate_frame(&mut self.frame_allocator)?;
+        let result = unsafe {
+            // SAFETY: the frames are unused.
+            self.map_frame(addr, frame, flags)
+        };
+        if result.is_err() {
+            unsafe {
+                // SAFETY: the frames were not mapped.
+                self.frame_allocator
+                    .deallocate_range(frame.start_address(), S::SIZE);
+            }
+        }
+        Some(result.map(|()| S::SIZE))
+    }
+
+    /// Maps the frames from `phys` at `addr` with the largest page that fits
+    /// in `remaining` bytes. Returns the size of the page.
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be used by something else.
+    pub(super) unsafe fn map_frames(
+        &mut self,
+        addr: VirtAddr,
+        phys: PhysAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        let size = self.largest_page(addr.as_u64() | phys.as_u64(), remaining);
+        unsafe {
+            // SAFETY: the caller guarantees that the frames are not used.
+            if size == Size1GiB::SIZE {
+                self.map_frame(addr, PhysFrame::<Size1GiB>::containing_address(phys), flags)?;
+            } else if size == Size2MiB::SIZE {
+                self.map_frame(addr, PhysFrame::<Size2MiB>::containing_address(phys), flags)?;
+            } else {
+                self.map_frame(addr, PhysFrame::<Size4KiB>::containing_address(phys), flags)?;
+            }
+        }
+        Ok(size)
+    }
+
+    unsafe fn map_frame<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(addr);
+        unsafe {
+            // SAFETY: the caller guarantees that the frame is unused.
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+        }
+        .map(MapperFlush::flush)
+        .map_err(|error| small_error(&error))
+    }
+
+    /// The size of the page mapping `addr`.
+    #[must_use]
+    pub fn page_size(&self, addr: VirtAddr) -> Option<u64> {
+        match self.mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => Some(frame.size()),
+            _ => None,
+        }
+    }
+
+    /// The size of the page mapping `addr`, a page aligned address, once the
+    /// huge pages going out of `addr..end` are split.
+    fn page_in_range(&mut self, addr: VirtAddr, end: VirtAddr) -> Result<u64, PageError> {
+        loop {
+            let size = self.page_size(addr).ok_or(PageError::NotMapped)?;
+            if size == Size4KiB::SIZE || (addr.is_aligned(size) && end - addr >= size) {
+                return Ok(size);
+            }
+            self.split_huge_page(addr)?;
+        }
+    }
+
+    /// Replaces the huge page mapping `addr` by a table of the 512 pages of the
+    /// next smaller size, mapped to the same frames with the same flags.
+    fn split_huge_page(&mut self, addr: VirtAddr) -> Result<(), PageError> {
+        let table_frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(PageError::NoFrameToSplit)?;
+        let mut table: *mut PageTable = self.mapper.level_4_table();
+        for (level, index) in [
+            (4, addr.p4_index()),
+            (3, addr.p3_index()),
+            (2, addr.p2_index()),
+        ] {
+            let entry = unsafe {
+                // SAFETY: the table is a page table of the active mapping.
+                &mut (&mut *table)[index]
+            };
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                break;
+            }
+            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let size = entry_size(level);
+                let child_size = entry_size(level - 1);
+                // The 4 KiB pages have no `HUGE_PAGE` flag, the bit is the PAT one.
+                let child_flags = if level == 2 {
+                    entry.flags() - PageTableFlags::HUGE_PAGE
+                } else {
+                    entry.flags()
+                };
+                let frames = entry.addr().align_down(size);
+                let child_table = unsafe {
+                    // SAFETY: the frame is unused.
+                    &mut *phys_to_virt(table_frame.start_address()).as_mut_ptr::<PageTable>()
+                };
+                for (child, number) in child_table.iter_mut().zip(0_u64..) {
+                    child.set_addr(frames + number * child_size, child_flags);
+                }
+                // The child entries enforce the protections, a later change of
+                // their flags must not be restricted by the table entry.
+                let mut table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                    table_flags |= PageTableFlags::USER_ACCESSIBLE;
+                }
+                entry.set_addr(table_frame.start_address(), table_flags);
+                tlb::flush(addr);
+                return Ok(());
+            }
+            table = phys_to_virt(entry.addr()).as_mut_ptr();
+        }
+        unsafe {
+            // SAFETY: the frame was not used.
+            self.frame_allocator.deallocate_frame(table_frame);
+        }
+        Err(PageError::NotMapped)
+    }
+
+    /// Unmaps the page at `addr`, a page aligned address, after splitting the
+    /// huge pages going out of `addr..end`. Returns the start of the frames of
+    /// the page and its size.
+    pub(super) fn unmap_in(
+        &mut self,
+        addr: VirtAddr,
+        end: VirtAddr,
+    ) -> Result<(PhysAddr, u64), UnmapError> {
+        let size = self.page_in_range(addr, end)?;
+        let frame = if size == Size1GiB::SIZE {
+            self.unmap_sized::<Size1GiB>(addr)?
+        } else if size == Size2MiB::SIZE {
+            self.unmap_sized::<Size2MiB>(addr)?
+        } else {
+            self.unmap_sized::<Size4KiB>(addr)?
+        };
+        Ok((frame, size))
+    }
+
+    fn unmap_sized<S: PageSize>(&mut self, addr: VirtAddr) -> Result<PhysAddr, UnmapError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let (frame, flush) = self.mapper.unmap(Page::<S>::containing_address(addr))?;
+        flush.flush();
+        Ok(frame.start_address())
+    }
+
+    /// Replaces the flags of the page at `addr`, a page aligned address, after
+    /// splitting the huge pages going out of `addr..end`. Returns the size of
+    /// the page.
+    ///
+    /// # Safety
+    ///
+    /// The new flags must not break the users of the memory.
+    pub(super) unsafe fn update_flags_in(
+        &mut self,
+        addr: VirtAddr,
+        end: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<u64, FlagUpdateError> {
+        let size = self.page_in_range(addr, end)?;
+        unsafe {
+            // SAFETY: the caller guarantees that the flags are valid.
+            if size == Size1GiB::SIZE {
+                let page = Page::<Size1GiB>::containing_address(addr);
+                self.mapper.update_flags(page, flags)?.flush();
+            } else if size == Size2MiB::SIZE {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                self.mapper.update_flags(page, flags)?.flush();
+            } else {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                self.mapper.update_flags(page, flags)?.flush();
+            }
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod huge_tests {
+    use super::*;
+    use crate::memory::{page_walk, usize_from, MEMORY_MANAGER, PAGE_SIZE};
+    use crate::{assert, assert_eq};
+
+    #[test_case]
+    fn test_allocate_aligned_frame() {
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        let used = memory.frame_allocator.stats().used;
+        let frame: PhysFrame<Size2MiB> = memory.frame_allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        assert_eq!(
+            memory.frame_allocator.stats().used,
+            used + frame_count(Size2MiB::SIZE)
+        );
+        unsafe {
+            // SAFETY: the frames are not used.
+            memory
+                .frame_allocator
+                .deallocate_range(frame.start_address(), Size2MiB::SIZE);
+        }
+        assert_eq!(memory.frame_allocator.stats().used, used);
+
+        // The freed frame is reused whole.
+        let again: PhysFrame<Size2MiB> = memory.frame_allocator.allocate_frame().unwrap();
+        assert_eq!(again, frame);
+        unsafe {
+            // SAFETY: the frames are not used.
+            memory
+                .frame_allocator
+                .deallocate_range(again.start_address(), Size2MiB::SIZE);
+        }
+    }
+
+    #[test_case]
+    fn test_split_huge_page() {
+        let size = 2 * Size2MiB::SIZE;
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        let start = memory.vmalloc("huge", usize_from(size), 0).unwrap();
+        assert!(start.is_aligned(Size2MiB::SIZE));
+        assert_eq!(memory.page_size(start), Some(Size2MiB::SIZE));
+        assert_eq!(
+            memory.page_size(start + Size2MiB::SIZE),
+            Some(Size2MiB::SIZE)
+        );
+
+        let before = start + Size2MiB::SIZE - PAGE_SIZE;
+        let hole = start + Size2MiB::SIZE;
+        let after = hole + PAGE_SIZE;
+        unsafe {
+            // SAFETY: the region is mapped and only used by this test.
+            before.as_mut_ptr::<u64>().write(1);
+            after.as_mut_ptr::<u64>().write(2);
+        }
+        let phys_after = memory.translate(after).unwrap();
+
+        unsafe {
+            // SAFETY: the page is not used.
+            memory.unmap_range(hole, PAGE_SIZE).unwrap();
+        }
+        assert_eq!(memory.translate(hole), None);
+        assert_eq!(memory.page_size(before), Some(Size2MiB::SIZE));
+        assert_eq!(memory.page_size(after), Some(PAGE_SIZE as u64));
+        assert_eq!(memory.translate(after), Some(phys_after));
+        assert_eq!(unsafe { after.as_ptr::<u64>().read() }, 2);
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            // SAFETY: the page is not written to anymore.
+            memory.protect(before, PAGE_SIZE, flags).unwrap();
+        }
+        assert_eq!(memory.page_size(start), Some(PAGE_SIZE as u64));
+        assert_eq!(unsafe { before.as_ptr::<u64>().read() }, 1);
+        let flags_of = |memory: &MemoryManager, addr| match memory.mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => PageTableFlags::empty(),
+        };
+        assert!(!flags_of(memory, before).contains(PageTableFlags::WRITABLE));
+        assert!(flags_of(memory, start).contains(PageTableFlags::WRITABLE));
+
+        // The table entry of the split page does not restrict its pages.
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            // SAFETY: the page is only used by this test.
+            memory.protect(before, PAGE_SIZE, flags).unwrap();
+            before.as_mut_ptr::<u64>().write(3);
+        }
+        assert_eq!(unsafe { before.as_ptr::<u64>().read() }, 3);
+        let table_entry = page_walk(before)
+            .unwrap()
+            .steps()
+            .find(|step| step.level == 2)
+            .copied()
+            .unwrap();
+        assert!(!table_entry.flags.contains(PageTableFlags::NO_EXECUTE));
+
+        // `vfree` expects all the pages of the region mapped.
+        memory
+            .map_range(hole, PAGE_SIZE, PageTableFlags::PRESENT)
+            .unwrap();
+        unsafe {
+            // SAFETY: the region is not used anymore.
+            memory.vfree(start).unwrap();
+        }
+        assert_eq!(memory.translate(start), None);
+    }
+}
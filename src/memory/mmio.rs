@@ -0,0 +1,221 @@
+use core::mem;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::vmalloc::VmallocError;
+use super::{usize_from, MemoryManager, MEMORY_MANAGER, PAGE_SIZE};
+
+/// Flags of the MMIO pages, the device registers must not be cached.
+fn mmio_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+}
+
+/// Physical device memory mapped uncached in the vmalloc window, unmapped
+/// when dropped.
+#[derive(Debug)]
+pub struct MmioRegion {
+    /// Start of the vmalloc region, the page of `phys`.
+    region_start: VirtAddr,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    #[must_use]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start.as_mut_ptr()
+    }
+
+    #[must_use]
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The register of type `T` at `offset` bytes from the start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is not in the region or is not aligned.
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(mem::size_of::<T>())
+                .is_some_and(|end| end <= self.size),
+            "register at {offset:#x} outside of the MMIO region"
+        );
+        let register = (self.start + offset).as_mut_ptr::<T>();
+        assert!(register.is_aligned(), "unaligned register at {offset:#x}");
+        register
+    }
+
+    /// Reads the register of type `T` at `offset` bytes from the start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is not in the region or is not aligned.
+    #[must_use]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe {
+            // SAFETY: the register is mapped and aligned.
+            self.register::<T>(offset).read_volatile()
+        }
+    }
+
+    /// Writes the register of type `T` at `offset` bytes from the start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the register is not in the region or is not aligned.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe {
+            // SAFETY: the register is mapped and aligned.
+            self.register::<T>(offset).write_volatile(value);
+        }
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        if let Some(memory) = MEMORY_MANAGER.lock().as_mut() {
+            unsafe {
+                // SAFETY: the region is not used anymore.
+                memory.unmap_mmio(self.region_start);
+            }
+        }
+    }
+}
+
+/// Maps the device memory at `phys..phys + size` uncached.
+///
+/// # Safety
+///
+/// The physical range must be device memory, not RAM used by something else.
+///
+/// # Errors
+///
+/// Fails if the memory manager is not initialized, if the vmalloc window or
+/// its region table is full or if no frame is left for the page tables.
+pub unsafe fn map_mmio(phys: PhysAddr, size: usize) -> Result<MmioRegion, VmallocError> {
+    let mut memory = MEMORY_MANAGER.lock();
+    let memory = memory.as_mut().ok_or(VmallocError::NotInitialized)?;
+    // The offset in the page fits in any `usize`.
+    #[allow(clippy::cast_possible_truncation)]
+    let offset = (phys.as_u64() % PAGE_SIZE as u64) as usize;
+    let size_from_page = offset
+        .checked_add(size)
+        .ok_or(VmallocError::OutOfVirtualMemory)?;
+    let region_start = unsafe {
+        // SAFETY: the caller guarantees that the range is device memory.
+        memory.map_mmio(phys - offset as u64, size_from_page)?
+    };
+    Ok(MmioRegion {
+        region_start,
+        start: region_start + offset,
+        phys,
+        size,
+    })
+}
+
+impl MemoryManager {
+    /// Maps the frames from `phys`, page aligned, in a new vmalloc region. A
+    /// large region is mapped with huge pages where `phys` is aligned for them.
+    unsafe fn map_mmio(&mut self, phys: PhysAddr, size: usize) -> Result<VirtAddr, VmallocError> {
+        let align = usize_from(self.largest_page(phys.as_u64(), size as u64));
+        let region = self
+            .regions
+            .reserve_aligned("mmio", size, 0, false, false, align)?;
+        let end = region.end();
+        let mut addr = region.start;
+        while addr < end {
+            let mapped = unsafe {
+                // SAFETY: the caller guarantees that the frames are device memory.
+                self.map_frames(addr, phys + (addr - region.start), end - addr, mmio_flags())
+            };
+            match mapped {
+                Ok(size) => addr += size,
+                Err(error) => {
+                    unsafe {
+                        // SAFETY: the region is not used yet.
+                        self.unmap_mmio_pages(region.start, addr);
+                    }
+                    self.regions.remove(region.start);
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(region.start)
+    }
+
+    /// Unmaps the MMIO region starting at `start` and releases it, the frames
+    /// belong to the device.
+    unsafe fn unmap_mmio(&mut self, start: VirtAddr) {
+        let Some(region) = self.regions.remove(start) else {
+            return;
+        };
+        unsafe {
+            // SAFETY: the caller guarantees that the region is unused.
+            self.unmap_mmio_pages(region.start, region.end());
+        }
+    }
+
+    /// Unmaps the pages from `start` until `end`, excluded.
+    unsafe fn unmap_mmio_pages(&mut self, start: VirtAddr, end: VirtAddr) {
+        let mut addr = start;
+        while addr < end {
+            match self.unmap_in(addr, end) {
+                Ok((_, size)) => addr += size,
+                Err(_) => addr += PAGE_SIZE,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod mmio_tests {
+    use super::*;
+    use crate::{assert, assert_eq};
+
+    /// The local APIC registers of the boot processor.
+    const LOCAL_APIC: u64 = 0xFEE0_0000;
+    const LOCAL_APIC_VERSION: usize = 0x30;
+
+    #[test_case]
+    fn test_map_local_apic() {
+        let apic = unsafe { map_mmio(PhysAddr::new(LOCAL_APIC), PAGE_SIZE) }.unwrap();
+        let version: u32 = apic.read(LOCAL_APIC_VERSION);
+        // The version is in the low byte, QEMU emulates a 0x14.
+        assert!(version & 0xFF != 0);
+
+        let start = VirtAddr::from_ptr(apic.as_ptr());
+        let translated = MEMORY_MANAGER.lock().as_ref().unwrap().translate(start);
+        assert_eq!(translated, Some(PhysAddr::new(LOCAL_APIC)));
+
+        drop(apic);
+        let translated = MEMORY_MANAGER.lock().as_ref().unwrap().translate(start);
+        assert_eq!(translated, None);
+    }
+
+    #[test_case]
+    fn test_unaligned_region() {
+        let phys = PhysAddr::new(LOCAL_APIC + LOCAL_APIC_VERSION as u64);
+        let region = unsafe { map_mmio(phys, 4) }.unwrap();
+        assert_eq!(region.as_ptr() as usize % PAGE_SIZE, LOCAL_APIC_VERSION);
+        let translated = MEMORY_MANAGER
+            .lock()
+            .as_ref()
+            .unwrap()
+            .translate(VirtAddr::from_ptr(region.as_ptr()));
+        assert_eq!(translated, Some(phys));
+    }
+}
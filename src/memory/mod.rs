@@ -0,0 +1,775 @@
+mod huge;
+pub mod mmio;
+pub mod sections;
+pub mod stack;
+pub mod user;
+pub mod vmalloc;
+pub mod walk;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use spin::Once;
+use x86_64::{
+    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        frame::PhysFrameRange,
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::irq_mutex::IrqMutex;
+
+pub use mmio::{map_mmio, MmioRegion};
+pub use stack::overflowed_stack;
+pub use user::{copy_from_user, copy_to_user, UserCopyError};
+use vmalloc::{VirtualRegions, VmallocError};
+pub use walk::{dump_mappings, page_walk};
+
+/// The memory manager, set up by `init`.
+///
+/// The interrupts are disabled while it is locked. The code holding it must
+/// not touch the pages of a lazy region for the first time, the fault cannot
+/// be resolved then, and must not allocate more than the kernel heap has
+/// free, the heap cannot grow then.
+pub static MEMORY_MANAGER: IrqMutex<Option<MemoryManager>> = IrqMutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Returns the virtual address of `addr` in the mapping of the complete
+/// physical memory.
+///
+/// # Panics
+///
+/// Panics if called before `init`.
+#[must_use]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .r#try()
+        .expect("memory not initialized");
+    *offset + addr.as_u64()
+}
+
+/// The inverse of `phys_to_virt`, `addr` must be in the mapping of the
+/// complete physical memory.
+///
+/// # Panics
+///
+/// Panics if called before `init`.
+#[must_use]
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .r#try()
+        .expect("memory not initialized");
+    PhysAddr::new(addr - *offset)
+}
+
+/// A `FrameAllocator` that returns usable frames from the bootloader's memory map.
+///
+/// The frames are taken in order from the usable regions, then the
+/// deallocated ones are reused first. Both operations are O(1).
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    /// Index in the memory map of the region new frames are taken from.
+    region: usize,
+    /// Address of the next frame never allocated in that region.
+    next: u64,
+    /// Top of the stack of deallocated frames, each one stores the next.
+    recycled: Option<PhysFrame>,
+    /// The same for the deallocated 2 MiB and 1 GiB frames, reused whole by
+    /// the huge pages and split when no smaller frame is left.
+    recycled_2mib: Option<PhysFrame<Size2MiB>>,
+    recycled_1gib: Option<PhysFrame<Size1GiB>>,
+    total: usize,
+    used: usize,
+}
+
+/// Frame counts of a frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+}
+
+impl FrameStats {
+    #[must_use]
+    pub fn free(&self) -> usize {
+        self.total - self.used
+    }
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a `FrameAllocator` from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are
+    /// marked as `USABLE` in it are really unused.
+    #[must_use]
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        let total = memory_map
+            .iter()
+            .filter_map(usable_range)
+            .map(Iterator::count)
+            .sum();
+        BootInfoFrameAllocator {
+            memory_map,
+            region: 0,
+            next: 0,
+            recycled: None,
+            recycled_2mib: None,
+            recycled_1gib: None,
+            total,
+            used: 0,
+        }
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            used: self.used,
+        }
+    }
+}
+
+/// The frames of a usable region, the partial frames at its ends are left out.
+fn usable_range(region: &MemoryRegion) -> Option<PhysFrameRange> {
+    if region.region_type != MemoryRegionType::Usable {
+        return None;
+    }
+    let start = PhysAddr::new(region.range.start_addr()).align_up(Size4KiB::SIZE);
+    let end = PhysAddr::new(region.range.end_addr()).align_down(Size4KiB::SIZE);
+    (start < end).then(|| {
+        PhysFrame::range(
+            PhysFrame::containing_address(start),
+            PhysFrame::containing_address(end),
+        )
+    })
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = unsafe {
+            // SAFETY: the stack only holds deallocated frames.
+            pop_frame(&mut self.recycled)
+        } {
+            self.used += 1;
+            return Some(frame);
+        }
+
+        // Each region is passed once, so the search is O(1) amortized.
+        while let Some(region) = self.memory_map.get(self.region) {
+            if let Some(range) = usable_range(region) {
+                let start = range.start.start_address().as_u64().max(self.next);
+                if start < range.end.start_address().as_u64() {
+                    self.next = start + Size4KiB::SIZE;
+                    self.used += 1;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+                }
+            }
+            self.region += 1;
+            self.next = 0;
+        }
+        if self.split_recycled::<Size2MiB>()
+            || (self.split_recycled::<Size1GiB>() && self.split_recycled::<Size2MiB>())
+        {
+            return self.allocate_frame();
+        }
+        None
+    }
+}
+
+/// Puts `frame` on top of the stack of deallocated frames `top`.
+///
+/// # Safety
+///
+/// The frame must be unused.
+unsafe fn push_frame<S: PageSize>(top: &mut Option<PhysFrame<S>>, frame: PhysFrame<S>) {
+    let next_ptr: *mut Option<PhysFrame<S>> = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        // SAFETY: the caller guarantees that the frame is unused.
+        next_ptr.write(*top);
+    }
+    *top = Some(frame);
+}
+
+/// Takes the frame on top of the stack of deallocated frames `top`.
+///
+/// # Safety
+///
+/// The frames of the stack must be unused.
+unsafe fn pop_frame<S: PageSize>(top: &mut Option<PhysFrame<S>>) -> Option<PhysFrame<S>> {
+    let frame = (*top)?;
+    let next_ptr: *const Option<PhysFrame<S>> = phys_to_virt(frame.start_address()).as_ptr();
+    *top = unsafe {
+        // SAFETY: the frame is unused and holds the next one.
+        next_ptr.read()
+    };
+    Some(frame)
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe {
+            // SAFETY: the caller guarantees that the frame is unused.
+            push_frame(&mut self.recycled, frame);
+        }
+        self.used -= 1;
+    }
+}
+
+unsafe fn active_level_4_table_mut(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    unsafe {
+        // SAFETY: the pointer is valid.
+        &mut *page_table_ptr
+    }
+}
+
+/// Sets up `MEMORY_MANAGER` with the active page table and the usable frames
+/// of `memory_map`, and enforces the protection flags of the kernel sections.
+///
+/// # Safety
+///
+/// This function is unsage because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset` and that the memory map is valid. Also, this
+/// function must be only called once to avoid aliasing `&mut` references
+/// (which is undefined behavior).
+///
+/// # Panics
+///
+/// Panics if a page of the kernel is not mapped, or if the boot stack is not
+/// where Cargo.toml asks the bootloader to map it.
+pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    let level_4_table = unsafe { active_level_4_table_mut(physical_memory_offset) };
+    let mapper = unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) };
+    let frame_allocator = unsafe { BootInfoFrameAllocator::new(memory_map) };
+    let max_page_size = if crate::cpu::supports_1gib_pages() {
+        Size1GiB::SIZE
+    } else {
+        Size2MiB::SIZE
+    };
+    let mut memory = MemoryManager {
+        mapper,
+        frame_allocator,
+        regions: VirtualRegions::new(),
+        max_page_size,
+    };
+    unsafe {
+        // SAFETY: the kernel does not write to its read only sections.
+        sections::enable_protection();
+    }
+    memory.protect_kernel().expect("kernel segment not mapped");
+    memory
+        .add_boot_stack()
+        .expect("boot stack not at the address given to the bootloader");
+    *MEMORY_MANAGER.lock() = Some(memory);
+}
+
+const PAGE_SIZE: usize = 4096;
+
+/// Owns the page table and the frame allocator.
+pub struct MemoryManager {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    regions: VirtualRegions,
+    /// The largest page size supported by the processor.
+    max_page_size: u64,
+}
+
+impl MemoryManager {
+    #[must_use]
+    pub fn frame_allocator(&mut self) -> &mut BootInfoFrameAllocator {
+        &mut self.frame_allocator
+    }
+
+    #[must_use]
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_allocator.stats()
+    }
+
+    /// Maps the pages of `start..start + size` to new frames with the given
+    /// flags, with 2 MiB and 1 GiB pages where the range is aligned for them
+    /// and aligned frames are left.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no frame is left or if a page is already mapped, the pages
+    /// mapped before the failure are unmapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a page mapped before the failure is not mapped anymore.
+    pub fn map_range(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let (start, end) = page_bounds(start, size);
+        let mut addr = start;
+        while addr < end {
+            match self.map_new_page(addr, end - addr, flags) {
+                Ok(size) => addr += size,
+                Err(error) => {
+                    unsafe {
+                        // SAFETY: the pages were mapped just before, they are not used yet.
+                        self.unmap_range(start, usize_from(addr - start))
+                            .expect("page mapped by `map_range` is not mapped");
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn map_page(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe {
+            // SAFETY: the frame is unused.
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+        } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(error) => {
+                unsafe {
+                    // SAFETY: the frame was not mapped.
+                    self.frame_allocator.deallocate_frame(frame);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Unmaps the pages of `start..start + size` and gives their frames back
+    /// to the frame allocator. The huge pages partly in the range are split.
+    ///
+    /// # Safety
+    ///
+    /// The memory must not be used anymore.
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first page that is not mapped, or that is a huge page that
+    /// cannot be split for lack of frames, the pages before it are unmapped.
+    pub unsafe fn unmap_range(&mut self, start: VirtAddr, size: usize) -> Result<(), UnmapError> {
+        let (mut addr, end) = page_bounds(start, size);
+        while addr < end {
+            let (frame, size) = self.unmap_in(addr, end)?;
+            unsafe {
+                // SAFETY: the caller guarantees that the memory is unused.
+                self.frame_allocator.deallocate_range(frame, size);
+            }
+            addr += size;
+        }
+        Ok(())
+    }
+
+    unsafe fn unmap_page(&mut self, page: Page) -> Result<(), UnmapError> {
+        let (frame, flush) = self.mapper.unmap(page)?;
+        flush.flush();
+        unsafe {
+            // SAFETY: the caller guarantees that the page is unused.
+            self.frame_allocator.deallocate_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address `addr` is mapped to.
+    #[must_use]
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+
+    /// Replaces the flags of the pages of `start..start + size`, the huge pages
+    /// partly in the range are split.
+    ///
+    /// # Safety
+    ///
+    /// The new flags must not break the users of the memory, for example by
+    /// making read only memory that is written to.
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first page that is not mapped, or that is a huge page that
+    /// cannot be split for lack of frames, the pages before it are updated.
+    pub unsafe fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let (mut addr, end) = page_bounds(start, size);
+        while addr < end {
+            addr += unsafe {
+                // SAFETY: the caller guarantees that the flags are valid.
+                self.update_flags_in(addr, end, flags)?
+            };
+        }
+        Ok(())
+    }
+
+    /// Maps `count` writable pages in the vmalloc window.
+    ///
+    /// # Errors
+    ///
+    /// Same as `vmalloc`.
+    pub fn alloc_pages(&mut self, count: usize) -> Result<VirtAddr, VmallocError> {
+        self.vmalloc("pages", count * PAGE_SIZE, 0)
+    }
+
+    /// Maps a writable, non executable region of `size` bytes, rounded up to
+    /// pages, in the vmalloc window. The `guard_pages` on both sides of it are
+    /// left unmapped. A large region is aligned to be mapped with huge pages.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window or the region table is full or if no frame is left.
+    pub fn vmalloc(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        guard_pages: usize,
+    ) -> Result<VirtAddr, VmallocError> {
+        let align = usize_from(self.largest_page(0, size as u64));
+        let region = self
+            .regions
+            .reserve_aligned(name, size, guard_pages, true, false, align)?;
+        if let Err(error) = self.map_range(
+            region.start,
+            region.size,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ) {
+            self.regions.remove(region.start);
+            return Err(error.into());
+        }
+        Ok(region.start)
+    }
+
+    /// Reserves a region of `size` bytes, rounded up to pages, in the vmalloc
+    /// window without mapping it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window or the region table is full.
+    pub fn reserve(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        guard_pages: usize,
+    ) -> Result<VirtAddr, VmallocError> {
+        Ok(self
+            .regions
+            .reserve(name, size, guard_pages, false, false)?
+            .start)
+    }
+
+    /// Reserves a writable, non executable region of `size` bytes, rounded up
+    /// to pages, in the vmalloc window. Its pages are mapped to zeroed frames
+    /// on their first access, by `handle_page_fault`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the window or the region table is full.
+    pub fn vmalloc_lazy(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        guard_pages: usize,
+    ) -> Result<VirtAddr, VmallocError> {
+        Ok(self
+            .regions
+            .reserve(name, size, guard_pages, true, true)?
+            .start)
+    }
+
+    /// Maps the page of `addr` to a zeroed frame if `addr` is in a lazy region.
+    ///
+    /// Returns whether the page is mapped.
+    fn map_lazy_page(&mut self, addr: VirtAddr) -> bool {
+        if !self.regions.find(addr).is_some_and(|region| region.lazy) {
+            return false;
+        }
+        let Some(frame) = self.frame_allocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            // SAFETY: the frame is unused.
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE);
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match unsafe {
+            // SAFETY: the frame is unused and the page is in a region.
+            self.mapper.map_to(
+                Page::containing_address(addr),
+                frame,
+                flags,
+                &mut self.frame_allocator,
+            )
+        } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(error) => {
+                unsafe {
+                    // SAFETY: the frame was not mapped.
+                    self.frame_allocator.deallocate_frame(frame);
+                }
+                matches!(error, MapToError::PageAlreadyMapped(_))
+            }
+        }
+    }
+
+    /// Releases the region starting at `start`, the frames of a region
+    /// from `vmalloc` go back to the frame allocator.
+    ///
+    /// # Safety
+    ///
+    /// The memory of the region must not be used anymore, and the pages of a
+    /// region from `reserve` must be unmapped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no region starts at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a page of a region from `vmalloc` is not mapped.
+    pub unsafe fn vfree(&mut self, start: VirtAddr) -> Result<(), VmallocError> {
+        let region = self
+            .regions
+            .remove(start)
+            .ok_or(VmallocError::NotAllocated)?;
+        if region.lazy {
+            for page in pages(region.start, region.size) {
+                unsafe {
+                    // SAFETY: the caller guarantees that the region is unused,
+                    // the pages never accessed are not mapped.
+                    let _ = self.unmap_page(page);
+                }
+            }
+        } else if region.backed {
+            unsafe {
+                // SAFETY: the caller guarantees that the region is unused.
+                self.unmap_range(region.start, region.size)
+                    .expect("page of a vmalloc region is not mapped");
+            }
+        }
+        Ok(())
+    }
+
+    /// The regions of the vmalloc window.
+    #[must_use]
+    pub fn regions(&self) -> &VirtualRegions {
+        &self.regions
+    }
+}
+
+/// Resolves a page fault on a page of a lazy region not accessed yet.
+///
+/// Returns whether the access can be retried, the other faults are fatal, as
+/// well as the ones of code holding `MEMORY_MANAGER`.
+#[must_use]
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    // The fault may come from code holding the lock.
+    let Some(mut memory) = MEMORY_MANAGER.try_lock() else {
+        return false;
+    };
+    memory
+        .as_mut()
+        .is_some_and(|memory| memory.map_lazy_page(addr))
+}
+
+/// The page aligned bounds of `start..start + size`.
+fn page_bounds(start: VirtAddr, size: usize) -> (VirtAddr, VirtAddr) {
+    let page_size = PAGE_SIZE as u64;
+    (
+        start.align_down(page_size),
+        (start + size).align_up(page_size),
+    )
+}
+
+/// A size within the address space.
+fn usize_from(size: u64) -> usize {
+    // The kernel only runs on 64 bits.
+    #[allow(clippy::cast_possible_truncation)]
+    let size = size as usize;
+    size
+}
+
+/// The pages of `start..start + size`.
+fn pages(start: VirtAddr, size: usize) -> PageRangeInclusive {
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1_u64);
+    Page::range_inclusive(start_page, end_page)
+}
+
+#[cfg(test)]
+mod memory_tests {
+    use bootloader::bootinfo::FrameRange;
+
+    use super::*;
+    use crate::{assert, assert_eq};
+
+    fn memory_map(
+        map: &'static Once<MemoryMap>,
+        regions: &[(u64, u64, MemoryRegionType)],
+    ) -> &'static MemoryMap {
+        map.call_once(|| {
+            let mut memory_map = MemoryMap::new();
+            for &(start, end, region_type) in regions {
+                memory_map.add_region(MemoryRegion {
+                    range: FrameRange::new(start, end),
+                    region_type,
+                });
+            }
+            memory_map
+        })
+    }
+
+    fn frame(addr: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    #[test_case]
+    fn test_frames_of_usable_regions() {
+        static MAP: Once<MemoryMap> = Once::new();
+        let memory_map = memory_map(
+            &MAP,
+            &[
+                (0x1000, 0x3000, MemoryRegionType::Usable),
+                (0x3000, 0x8000, MemoryRegionType::Reserved),
+                (0x8000, 0x9000, MemoryRegionType::Usable),
+            ],
+        );
+        let mut frame_allocator = unsafe {
+            // SAFETY: the frames are not written to.
+            BootInfoFrameAllocator::new(memory_map)
+        };
+        assert_eq!(frame_allocator.stats(), FrameStats { total: 3, used: 0 });
+
+        assert_eq!(frame_allocator.allocate_frame(), Some(frame(0x1000)));
+        assert_eq!(frame_allocator.allocate_frame(), Some(frame(0x2000)));
+        assert_eq!(frame_allocator.allocate_frame(), Some(frame(0x8000)));
+        assert_eq!(frame_allocator.allocate_frame(), None::<PhysFrame>);
+        assert_eq!(frame_allocator.stats(), FrameStats { total: 3, used: 3 });
+    }
+
+    #[test_case]
+    fn test_deallocated_frames_are_reused() {
+        static MAP: Once<MemoryMap> = Once::new();
+        let borrowed = MEMORY_MANAGER
+            .lock()
+            .as_mut()
+            .and_then(|memory| memory.frame_allocator().allocate_frame())
+            .unwrap();
+        let start = borrowed.start_address().as_u64();
+        let memory_map = memory_map(
+            &MAP,
+            &[(start, start + Size4KiB::SIZE, MemoryRegionType::Usable)],
+        );
+        let mut frame_allocator = unsafe {
+            // SAFETY: the only usable frame is borrowed from `MEMORY_MANAGER`.
+            BootInfoFrameAllocator::new(memory_map)
+        };
+
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame, borrowed);
+        assert_eq!(frame_allocator.allocate_frame(), None::<PhysFrame>);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.stats().free(), 1);
+        assert_eq!(frame_allocator.allocate_frame(), Some(borrowed));
+
+        unsafe {
+            // SAFETY: the frame is not used anymore.
+            MEMORY_MANAGER
+                .lock()
+                .as_mut()
+                .unwrap()
+                .frame_allocator()
+                .deallocate_frame(borrowed);
+        }
+    }
+
+    #[test_case]
+    fn test_map_translate_protect_unmap() {
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        let start = memory.alloc_pages(2).unwrap();
+        // The page tables of the range may take frames too.
+        let used = memory.frame_stats().used;
+        let page = start + Size4KiB::SIZE;
+        unsafe { page.as_mut_ptr::<u64>().write(42) };
+        let phys = memory.translate(page).unwrap();
+        assert_eq!(unsafe { phys_to_virt(phys).as_ptr::<u64>().read() }, 42);
+
+        unsafe {
+            memory
+                .protect(start, 2 * PAGE_SIZE, PageTableFlags::PRESENT)
+                .unwrap();
+        }
+        assert_eq!(unsafe { page.as_ptr::<u64>().read() }, 42);
+
+        unsafe { memory.unmap_range(start, 2 * PAGE_SIZE) }.unwrap();
+        assert_eq!(memory.translate(page), None);
+        assert_eq!(memory.frame_stats().used, used - 2);
+        // The pages are unmapped, only the region is left.
+        memory.regions.remove(start);
+    }
+
+    #[test_case]
+    fn test_lazy_region() {
+        let start = MEMORY_MANAGER
+            .lock()
+            .as_mut()
+            .unwrap()
+            .vmalloc_lazy("lazy", 2 * PAGE_SIZE, 0)
+            .unwrap();
+        let second_page = start + PAGE_SIZE;
+        let translate = |addr| MEMORY_MANAGER.lock().as_ref().unwrap().translate(addr);
+        assert_eq!(translate(start), None);
+
+        // The accesses fault and the pages are mapped by the handler.
+        assert_eq!(unsafe { start.as_ptr::<u64>().read_volatile() }, 0);
+        unsafe { second_page.as_mut_ptr::<u64>().write_volatile(42) };
+        assert!(translate(start).is_some());
+        assert_eq!(unsafe { second_page.as_ptr::<u64>().read_volatile() }, 42);
+
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        unsafe { memory.vfree(start) }.unwrap();
+        assert_eq!(memory.translate(second_page), None);
+    }
+
+    #[test_case]
+    fn test_fault_outside_lazy_region() {
+        let addr = VirtAddr::new(vmalloc::VMALLOC_START + vmalloc::VMALLOC_SIZE - 1);
+        assert!(!handle_page_fault(addr, PageFaultErrorCode::empty()));
+    }
+}
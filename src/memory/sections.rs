@@ -0,0 +1,268 @@
+use core::{mem, slice};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{MemoryManager, PAGE_SIZE};
+
+extern "C" {
+    /// The ELF header of the kernel, in its first loaded segment.
+    static __ehdr_start: u8;
+}
+
+/// Offsets of `e_phoff`, `e_phentsize` and `e_phnum` in the ELF header.
+const PROGRAM_HEADERS_OFFSET: usize = 32;
+const PROGRAM_HEADER_SIZE_OFFSET: usize = 54;
+const PROGRAM_HEADER_COUNT_OFFSET: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+/// Loaded memory read only once relocated, in a writable `PT_LOAD` segment.
+pub const PT_GNU_RELRO: u32 = 0x6474_E552;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+/// A program header of the kernel ELF file.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    #[must_use]
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.vaddr)
+    }
+
+    #[must_use]
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.vaddr <= addr.as_u64() && addr.as_u64() - self.vaddr < self.mem_size
+    }
+
+    /// The flags of the pages of the segment, a writable segment is never
+    /// executable.
+    #[must_use]
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.kind == PT_GNU_RELRO {
+            return flags | PageTableFlags::NO_EXECUTE;
+        }
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 || self.flags & PF_W != 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+fn ehdr_field<T: Copy>(offset: usize) -> T {
+    unsafe {
+        // SAFETY: the linker defines `__ehdr_start` at the ELF header, which
+        // is mapped with the first segment.
+        (&raw const __ehdr_start)
+            .add(offset)
+            .cast::<T>()
+            .read_unaligned()
+    }
+}
+
+/// The program headers of the kernel, read from its ELF header.
+///
+/// # Panics
+///
+/// Panics if the size of the headers is not the one of `ProgramHeader`.
+#[must_use]
+pub fn program_headers() -> &'static [ProgramHeader] {
+    let entry_size: u16 = ehdr_field(PROGRAM_HEADER_SIZE_OFFSET);
+    assert_eq!(usize::from(entry_size), mem::size_of::<ProgramHeader>());
+    // The headers follow the ELF header in the first segment.
+    #[allow(clippy::cast_possible_truncation)]
+    let offset = ehdr_field::<u64>(PROGRAM_HEADERS_OFFSET) as usize;
+    let count: u16 = ehdr_field(PROGRAM_HEADER_COUNT_OFFSET);
+    // The linker aligns the headers.
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe {
+        // SAFETY: the headers are mapped with the ELF header and are aligned.
+        slice::from_raw_parts(
+            (&raw const __ehdr_start)
+                .add(offset)
+                .cast::<ProgramHeader>(),
+            usize::from(count),
+        )
+    }
+}
+
+/// Makes the processor honor the `NO_EXECUTE` flag and the read only pages in
+/// ring 0.
+///
+/// # Safety
+///
+/// The code running must not write to its read only pages.
+pub(super) unsafe fn enable_protection() {
+    unsafe {
+        // SAFETY: the processor is 64 bits, it supports `NO_EXECUTE`, and the
+        // caller guarantees that the read only pages are not written to.
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// The flags of the kernel page at `page`, merged from the segments sharing
+/// it: the page is writable if one of its bytes is, and executable if one of
+/// its bytes is, even when that breaks W^X.
+fn merged_page_flags(headers: &[ProgramHeader], page: VirtAddr) -> PageTableFlags {
+    let page_end = page.as_u64() + PAGE_SIZE as u64;
+    let mut writable = false;
+    let mut executable = false;
+    for segment in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        let start = segment.vaddr.max(page.as_u64());
+        let end = (segment.vaddr + segment.mem_size).min(page_end);
+        if start >= end {
+            continue;
+        }
+        executable |= segment.flags & PF_X != 0;
+        // The relro part of a writable segment is read only.
+        let relro = headers.iter().any(|header| {
+            header.kind == PT_GNU_RELRO
+                && header.vaddr <= start
+                && end <= header.vaddr + header.mem_size
+        });
+        writable |= segment.flags & PF_W != 0 && !relro;
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+impl MemoryManager {
+    /// Remaps the loaded segments of the kernel with the flags of their
+    /// program headers: text read only, rodata and relro read only and non
+    /// executable, data and bss writable and non executable. A page shared by
+    /// segments gets the permissions of all of them.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a page of a segment is not mapped.
+    pub(super) fn protect_kernel(&mut self) -> Result<(), FlagUpdateError> {
+        let headers = program_headers();
+        let segments = headers
+            .iter()
+            .filter(|header| header.kind == PT_LOAD && header.mem_size > 0);
+        for segment in segments {
+            let start = segment.start().align_down(PAGE_SIZE as u64);
+            let end = (segment.start() + segment.mem_size).align_up(PAGE_SIZE as u64);
+            for page in (start.as_u64()..end.as_u64()).step_by(PAGE_SIZE) {
+                let page = VirtAddr::new(page);
+                unsafe {
+                    // SAFETY: the flags allow what the segments are linked with.
+                    self.protect(page, PAGE_SIZE, merged_page_flags(headers, page))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod sections_tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::memory::page_walk;
+    use crate::{assert, assert_eq};
+
+    /// The flags of the page mapping `addr`.
+    fn page_flags(addr: VirtAddr) -> PageTableFlags {
+        page_walk(addr).unwrap().steps().last().unwrap().flags
+    }
+
+    /// An address in the kernel text.
+    fn text() -> VirtAddr {
+        let function: fn() -> &'static [ProgramHeader] = program_headers;
+        VirtAddr::new(function as usize as u64)
+    }
+
+    #[test_case]
+    fn test_program_headers() {
+        let text = text();
+        let segment = program_headers()
+            .iter()
+            .find(|header| header.kind == PT_LOAD && header.contains(text))
+            .unwrap();
+        assert_eq!(segment.flags & (PF_X | PF_W), PF_X);
+        assert_eq!(segment.page_flags(), PageTableFlags::PRESENT);
+    }
+
+    #[test_case]
+    fn test_kernel_sections_flags() {
+        static DATA: u8 = 0;
+        static mut BSS: [u8; 8] = [0; 8];
+
+        let text = page_flags(text());
+        assert!(!text.contains(PageTableFlags::WRITABLE));
+        assert!(!text.contains(PageTableFlags::NO_EXECUTE));
+
+        let rodata = page_flags(VirtAddr::from_ptr(&raw const DATA));
+        assert!(!rodata.contains(PageTableFlags::WRITABLE));
+        assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+
+        let bss = page_flags(VirtAddr::from_ptr(&raw const BSS));
+        assert!(bss.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        let heap = Box::new(0_u64);
+        let heap = page_flags(VirtAddr::from_ptr(&raw const *heap));
+        assert!(heap.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn test_protection_enabled() {
+        assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+        assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    }
+
+    #[test_case]
+    fn test_shared_pages_merge_flags() {
+        let segment = |kind, flags, vaddr, mem_size| ProgramHeader {
+            kind,
+            flags,
+            offset: 0,
+            vaddr,
+            paddr: 0,
+            file_size: mem_size,
+            mem_size,
+            align: 0x1000,
+        };
+        let headers = [
+            segment(PT_LOAD, PF_X, 0x1000, 0x1800),
+            segment(PT_LOAD, 0, 0x2800, 0x800),
+            segment(PT_LOAD, PF_W, 0x3000, 0x2000),
+            segment(PT_GNU_RELRO, 0, 0x3000, 0x1800),
+        ];
+        let flags = |page| merged_page_flags(&headers, VirtAddr::new(page));
+        let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let writable = read_only | PageTableFlags::WRITABLE;
+
+        assert_eq!(flags(0x1000), PageTableFlags::PRESENT);
+        // The tail of the text shares its page with rodata.
+        assert_eq!(flags(0x2000), PageTableFlags::PRESENT);
+        assert_eq!(flags(0x3000), read_only);
+        // The end of the relro shares its page with data.
+        assert_eq!(flags(0x4000), writable);
+    }
+}
@@ -0,0 +1,128 @@
+use x86_64::VirtAddr;
+
+use super::vmalloc::{VirtualRegion, VmallocError};
+use super::{MemoryManager, MEMORY_MANAGER, PAGE_SIZE};
+
+/// The guard page of the stack set up by the bootloader, `kernel-stack-address`
+/// in the `package.metadata.bootloader` section of Cargo.toml.
+const BOOT_STACK_GUARD: u64 = 0x6666_0000_0000;
+/// Its `kernel-stack-size`, in pages.
+const BOOT_STACK_PAGES: usize = 512;
+
+impl MemoryManager {
+    /// Maps a stack of `size` bytes, rounded up to pages, with an unmapped
+    /// guard page below it. Returns the top of the stack.
+    ///
+    /// # Errors
+    ///
+    /// Same as `vmalloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mapped region cannot be found back.
+    pub fn alloc_stack(
+        &mut self,
+        name: &'static str,
+        size: usize,
+    ) -> Result<VirtAddr, VmallocError> {
+        let start = self.vmalloc(name, size, 1)?;
+        let region = self
+            .regions
+            .find_mut(start)
+            .expect("region mapped by `vmalloc` not found");
+        region.stack = true;
+        Ok(region.end())
+    }
+
+    /// Registers the stack set up by the bootloader, mapped above its guard
+    /// page at `BOOT_STACK_GUARD`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the stack is not mapped there, if the region table is full or
+    /// if the stack overlaps a region.
+    pub(super) fn add_boot_stack(&mut self) -> Result<(), VmallocError> {
+        let guard = VirtAddr::new(BOOT_STACK_GUARD);
+        let start = guard + PAGE_SIZE;
+        let size = BOOT_STACK_PAGES * PAGE_SIZE;
+        if self.translate(guard).is_some()
+            || self.translate(start).is_none()
+            || self.translate(start + (size - PAGE_SIZE)).is_none()
+        {
+            return Err(VmallocError::NotAllocated);
+        }
+        self.regions.insert(VirtualRegion {
+            name: "boot stack",
+            start,
+            size,
+            guard_pages: 1,
+            backed: false,
+            lazy: false,
+            stack: true,
+        })
+    }
+}
+
+/// The name of the stack whose guard page below it contains `addr`, the
+/// address of a page fault.
+#[must_use]
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    // The fault may come from code holding the lock.
+    let memory = MEMORY_MANAGER.try_lock()?;
+    memory
+        .as_ref()?
+        .regions
+        .find_guard(addr)
+        .filter(|region| region.stack && addr < region.start)
+        .map(|region| region.name)
+}
+
+#[cfg(test)]
+mod stack_tests {
+    use super::*;
+    use crate::{assert, assert_eq};
+
+    #[test_case]
+    fn test_alloc_stack() {
+        let size = 2 * PAGE_SIZE;
+        let top = MEMORY_MANAGER
+            .lock()
+            .as_mut()
+            .unwrap()
+            .alloc_stack("test stack", size)
+            .unwrap();
+        let bottom = top - size;
+        {
+            let memory = MEMORY_MANAGER.lock();
+            let memory = memory.as_ref().unwrap();
+            assert!(memory.translate(top - 1_u64).is_some());
+            assert!(memory.translate(bottom).is_some());
+            assert!(memory.translate(bottom - 1_u64).is_none());
+        }
+
+        assert_eq!(overflowed_stack(bottom - 1_u64), Some("test stack"));
+        assert_eq!(overflowed_stack(bottom - PAGE_SIZE), Some("test stack"));
+        // The guard page above is not hit by an overflow.
+        assert_eq!(overflowed_stack(top), None);
+        assert_eq!(overflowed_stack(bottom), None);
+
+        unsafe { MEMORY_MANAGER.lock().as_mut().unwrap().vfree(bottom) }.unwrap();
+        assert_eq!(overflowed_stack(bottom - 1_u64), None);
+    }
+
+    #[test_case]
+    fn test_boot_stack_registered() {
+        let marker = 0_u8;
+        let addr = VirtAddr::from_ptr(&raw const marker);
+        let memory = MEMORY_MANAGER.lock();
+        let stack = memory.as_ref().unwrap().regions.find(addr).copied();
+        drop(memory);
+
+        let stack = stack.unwrap();
+        assert_eq!(stack.name, "boot stack");
+        assert!(stack.stack);
+        assert_eq!(stack.start, VirtAddr::new(BOOT_STACK_GUARD) + PAGE_SIZE);
+        assert_eq!(stack.size, BOOT_STACK_PAGES * PAGE_SIZE);
+        assert_eq!(overflowed_stack(stack.start - 1_u64), Some("boot stack"));
+    }
+}
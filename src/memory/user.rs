@@ -0,0 +1,176 @@
+use core::arch::asm;
+use core::ptr;
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::page_walk;
+use crate::cpu;
+
+/// User memory is in the lower half of the address space.
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The address is not in a user accessible page, or not in a writable one
+    /// for a copy to it.
+    BadAddress(VirtAddr),
+}
+
+/// Checks that the pages of `start..start + size` are mapped user accessible,
+/// and writable if `write` is set.
+fn check_user_range(start: VirtAddr, size: usize, write: bool) -> Result<(), UserCopyError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let last = start
+        .as_u64()
+        .checked_add(size as u64 - 1)
+        .filter(|&last| last < USER_END)
+        .ok_or(UserCopyError::BadAddress(start))?;
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let first_page: Page = Page::containing_address(start);
+    let last_page = Page::containing_address(VirtAddr::new(last));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let addr = page.start_address();
+        let allowed = page_walk(addr).is_some_and(|walk| {
+            walk.mapping.is_some() && walk.steps().all(|step| step.flags.contains(required))
+        });
+        if !allowed {
+            return Err(UserCopyError::BadAddress(addr.max(start)));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` with the kernel allowed to access the user pages.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = cpu::smap_enabled();
+    if smap {
+        unsafe {
+            // SAFETY: SMAP is supported, `stac` only sets the AC flag.
+            asm!("stac", options(nostack));
+        }
+    }
+    let result = f();
+    if smap {
+        unsafe {
+            // SAFETY: SMAP is supported, `clac` only clears the AC flag.
+            asm!("clac", options(nostack));
+        }
+    }
+    result
+}
+
+/// Copies `dst.len()` bytes from the user memory at `src`.
+///
+/// # Errors
+///
+/// Fails, copying nothing, if the source is not in user accessible pages.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    check_user_range(src, dst.len(), false)?;
+    with_user_access(|| unsafe {
+        // SAFETY: the source is mapped and cannot overlap the kernel memory.
+        ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    });
+    Ok(())
+}
+
+/// Copies `src` to the user memory at `dst`.
+///
+/// # Errors
+///
+/// Fails, copying nothing, if the destination is not in writable user
+/// accessible pages.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    check_user_range(dst, src.len(), true)?;
+    with_user_access(|| unsafe {
+        // SAFETY: the destination is mapped and cannot overlap the kernel memory.
+        ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len());
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod user_tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::memory::{MEMORY_MANAGER, PAGE_SIZE};
+    use crate::test_utils;
+    use crate::{assert, assert_eq};
+
+    /// Maps two user pages, the second one read only.
+    fn map_writable_and_read_only() -> VirtAddr {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let start = test_utils::map_user_pages(2 * PAGE_SIZE, flags | PageTableFlags::WRITABLE);
+        unsafe {
+            // SAFETY: the pages are only used by the calling test.
+            MEMORY_MANAGER
+                .lock()
+                .as_mut()
+                .unwrap()
+                .protect(
+                    start + PAGE_SIZE,
+                    PAGE_SIZE,
+                    flags | PageTableFlags::USER_ACCESSIBLE,
+                )
+                .unwrap();
+        }
+        start
+    }
+
+    fn unmap_user_pages(start: VirtAddr) {
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        unsafe {
+            memory.unmap_range(start, 2 * PAGE_SIZE).unwrap();
+            memory.vfree(start).unwrap();
+        }
+    }
+
+    #[test_case]
+    fn test_copy_round_trip() {
+        let start = map_writable_and_read_only();
+        let addr = start + 100_u64;
+        copy_to_user(addr, b"hello").unwrap();
+        let mut read = [0; 5];
+        copy_from_user(&mut read, addr).unwrap();
+        assert_eq!(&read, b"hello");
+
+        // The read only page can be read from but not written to.
+        let read_only = start + PAGE_SIZE;
+        assert!(copy_from_user(&mut read, read_only - 2_u64).is_ok());
+        assert_eq!(
+            copy_to_user(read_only - 2_u64, b"hello"),
+            Err(UserCopyError::BadAddress(read_only))
+        );
+        unmap_user_pages(start);
+    }
+
+    #[test_case]
+    fn test_copy_rejects_kernel_memory() {
+        let kernel = Box::new([0_u8; 8]);
+        let addr = VirtAddr::from_ptr(kernel.as_ptr());
+        let mut read = [0; 8];
+        assert_eq!(
+            copy_from_user(&mut read, addr),
+            Err(UserCopyError::BadAddress(addr))
+        );
+        assert_eq!(
+            copy_to_user(addr, &read),
+            Err(UserCopyError::BadAddress(addr))
+        );
+
+        let higher_half = VirtAddr::new(0xFFFF_8000_0000_0000);
+        assert_eq!(
+            copy_from_user(&mut read, higher_half),
+            Err(UserCopyError::BadAddress(higher_half))
+        );
+        assert!(copy_from_user(&mut [], higher_half).is_ok());
+    }
+}
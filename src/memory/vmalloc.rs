@@ -0,0 +1,348 @@
+use core::fmt;
+
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::PAGE_SIZE;
+
+/// Start of the virtual range handed out by `MemoryManager::vmalloc`.
+pub const VMALLOC_START: u64 = 0x5555_0000_0000;
+pub const VMALLOC_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+/// The regions are kept in a fixed table, it must not need the heap.
+pub const MAX_REGIONS: usize = 64;
+
+/// A range of the vmalloc window in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    pub name: &'static str,
+    /// First usable address, after the guard pages.
+    pub start: VirtAddr,
+    /// Usable size, a multiple of the page size.
+    pub size: usize,
+    /// Unmapped pages kept on both sides of the region.
+    pub guard_pages: usize,
+    /// Whether the region is mapped to frames owned by the region.
+    pub backed: bool,
+    /// Whether the pages are mapped on their first access.
+    pub lazy: bool,
+    /// Whether the region is a stack, growing down towards its lower guard page.
+    pub stack: bool,
+}
+
+impl VirtualRegion {
+    const EMPTY: Self = Self {
+        name: "",
+        start: VirtAddr::zero(),
+        size: 0,
+        guard_pages: 0,
+        backed: false,
+        lazy: false,
+        stack: false,
+    };
+
+    #[must_use]
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Start of the region with its guard pages.
+    fn span_start(&self) -> VirtAddr {
+        self.start - self.guard_pages * PAGE_SIZE
+    }
+
+    /// End of the region with its guard pages.
+    fn span_end(&self) -> VirtAddr {
+        self.end() + self.guard_pages * PAGE_SIZE
+    }
+
+    #[must_use]
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Whether `addr` is in one of the guard pages of the region.
+    #[must_use]
+    pub fn guards(&self, addr: VirtAddr) -> bool {
+        self.span_start() <= addr && addr < self.span_end() && !self.contains(addr)
+    }
+}
+
+impl fmt::Display for VirtualRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..{:#x} {:>10} bytes {}",
+            self.start.as_u64(),
+            self.end().as_u64(),
+            self.size,
+            self.name
+        )?;
+        if !self.backed {
+            write!(f, " (reserved)")?;
+        }
+        if self.lazy {
+            write!(f, " (lazy)")?;
+        }
+        if self.stack {
+            write!(f, " (stack)")?;
+        }
+        if self.guard_pages > 0 {
+            write!(f, " ({} guard pages)", self.guard_pages)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum VmallocError {
+    /// No hole of the vmalloc window is big enough.
+    OutOfVirtualMemory,
+    /// The region table is full.
+    TooManyRegions,
+    /// The range overlaps another region.
+    Overlap,
+    /// No region starts at the address.
+    NotAllocated,
+    /// The memory manager is not initialized.
+    NotInitialized,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for VmallocError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        Self::Map(error)
+    }
+}
+
+/// The regions of the vmalloc window, sorted by address.
+pub struct VirtualRegions {
+    regions: [VirtualRegion; MAX_REGIONS],
+    len: usize,
+}
+
+impl VirtualRegions {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            regions: [VirtualRegion::EMPTY; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualRegion> {
+        self.regions[..self.len].iter()
+    }
+
+    /// The region containing `addr`, guard pages excluded.
+    #[must_use]
+    pub fn find(&self, addr: VirtAddr) -> Option<&VirtualRegion> {
+        self.iter().find(|region| region.contains(addr))
+    }
+
+    /// The region whose guard pages contain `addr`.
+    #[must_use]
+    pub fn find_guard(&self, addr: VirtAddr) -> Option<&VirtualRegion> {
+        self.iter().find(|region| region.guards(addr))
+    }
+
+    pub(super) fn find_mut(&mut self, addr: VirtAddr) -> Option<&mut VirtualRegion> {
+        self.regions[..self.len]
+            .iter_mut()
+            .find(|region| region.contains(addr))
+    }
+
+    /// Takes the first hole of the vmalloc window big enough for `size` bytes,
+    /// rounded up to pages, and the guard pages.
+    pub(super) fn reserve(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        guard_pages: usize,
+        backed: bool,
+        lazy: bool,
+    ) -> Result<VirtualRegion, VmallocError> {
+        self.reserve_aligned(name, size, guard_pages, backed, lazy, PAGE_SIZE)
+    }
+
+    /// Same as `reserve`, with the start of the region aligned on `align`, a
+    /// power of two multiple of the page size.
+    pub(super) fn reserve_aligned(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        guard_pages: usize,
+        backed: bool,
+        lazy: bool,
+        align: usize,
+    ) -> Result<VirtualRegion, VmallocError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmallocError::TooManyRegions);
+        }
+        let size = size
+            .max(1)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(VmallocError::OutOfVirtualMemory)?;
+        let guards = guard_pages
+            .checked_mul(PAGE_SIZE)
+            .ok_or(VmallocError::OutOfVirtualMemory)?;
+        let tail = guards
+            .checked_add(size)
+            .ok_or(VmallocError::OutOfVirtualMemory)?;
+
+        // The regions outside of the window are skipped.
+        let window_end = VirtAddr::new(VMALLOC_START + VMALLOC_SIZE);
+        let mut hole_start = VirtAddr::new(VMALLOC_START);
+        for index in 0..=self.len {
+            let hole_end = self.regions[..self.len]
+                .get(index)
+                .map_or(window_end, VirtualRegion::span_start)
+                .min(window_end);
+            let start = (hole_start.as_u64())
+                .checked_add(guards as u64)
+                .and_then(|start| start.checked_next_multiple_of(align as u64))
+                .filter(|&start| {
+                    start < hole_end.as_u64() && hole_end.as_u64() - start >= tail as u64
+                });
+            if let Some(start) = start {
+                let region = VirtualRegion {
+                    name,
+                    start: VirtAddr::new(start),
+                    size,
+                    guard_pages,
+                    backed,
+                    lazy,
+                    stack: false,
+                };
+                self.insert_at(index, region);
+                return Ok(region);
+            }
+            if let Some(region) = self.regions[..self.len].get(index) {
+                hole_start = hole_start.max(region.span_end());
+            }
+        }
+        Err(VmallocError::OutOfVirtualMemory)
+    }
+
+    /// Adds a region at a fixed address, outside of the vmalloc window.
+    pub(super) fn insert(&mut self, region: VirtualRegion) -> Result<(), VmallocError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmallocError::TooManyRegions);
+        }
+        let index = self
+            .iter()
+            .position(|other| other.span_start() >= region.span_end())
+            .unwrap_or(self.len);
+        let overlaps_previous = index
+            .checked_sub(1)
+            .is_some_and(|previous| self.regions[previous].span_end() > region.span_start());
+        if overlaps_previous {
+            return Err(VmallocError::Overlap);
+        }
+        self.insert_at(index, region);
+        Ok(())
+    }
+
+    fn insert_at(&mut self, index: usize, region: VirtualRegion) {
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
+    }
+
+    /// Removes the region starting at `start`.
+    pub(super) fn remove(&mut self, start: VirtAddr) -> Option<VirtualRegion> {
+        let index = self.iter().position(|region| region.start == start)?;
+        let region = self.regions[index];
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        Some(region)
+    }
+}
+
+impl Default for VirtualRegions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod vmalloc_tests {
+    use super::*;
+    use crate::memory::MEMORY_MANAGER;
+    use crate::{assert, assert_eq};
+
+    #[test_case]
+    fn test_reserve_first_fit() {
+        let mut regions = VirtualRegions::new();
+        let first = regions.reserve("first", 1, 1, false, false).unwrap();
+        let second = regions
+            .reserve("second", 2 * PAGE_SIZE, 0, false, false)
+            .unwrap();
+        assert_eq!(first.start, VirtAddr::new(VMALLOC_START) + PAGE_SIZE);
+        assert_eq!(first.size, PAGE_SIZE);
+        assert_eq!(second.start, first.end() + PAGE_SIZE);
+
+        // The hole left by the first region is reused.
+        regions.remove(first.start).unwrap();
+        let third = regions
+            .reserve("third", PAGE_SIZE, 0, false, false)
+            .unwrap();
+        assert_eq!(third.start, VirtAddr::new(VMALLOC_START));
+        let names: [_; 2] = core::array::from_fn(|i| regions.regions[i].name);
+        assert_eq!(names, ["third", "second"]);
+
+        assert!(regions.remove(first.start).is_none());
+        let aligned = regions
+            .reserve_aligned("aligned", 1, 1, false, false, 16 * PAGE_SIZE)
+            .unwrap();
+        assert_eq!(aligned.start.as_u64() % (16 * PAGE_SIZE as u64), 0);
+        assert!(aligned.span_start() >= second.span_end());
+        assert!(matches!(
+            regions.reserve(
+                "huge",
+                usize::try_from(VMALLOC_SIZE).unwrap(),
+                0,
+                false,
+                false
+            ),
+            Err(VmallocError::OutOfVirtualMemory)
+        ));
+    }
+
+    #[test_case]
+    fn test_insert_outside_window() {
+        let mut regions = VirtualRegions::new();
+        let fixed = VirtualRegion {
+            name: "fixed",
+            start: VirtAddr::new(VMALLOC_START / 2),
+            size: PAGE_SIZE,
+            guard_pages: 0,
+            backed: true,
+            lazy: true,
+            stack: false,
+        };
+        regions.insert(fixed).unwrap();
+        assert!(matches!(regions.insert(fixed), Err(VmallocError::Overlap)));
+
+        let reserved = regions.reserve("reserved", 1, 0, false, false).unwrap();
+        assert_eq!(reserved.start, VirtAddr::new(VMALLOC_START));
+        assert_eq!(regions.find(fixed.start + 1_u64), Some(&fixed));
+        assert_eq!(regions.find(fixed.end()), None);
+    }
+
+    #[test_case]
+    fn test_vmalloc_guard_pages() {
+        let mut memory = MEMORY_MANAGER.lock();
+        let memory = memory.as_mut().unwrap();
+        let start = memory.vmalloc("test", 3 * PAGE_SIZE, 1).unwrap();
+
+        for page in 0..3 {
+            assert!(memory.translate(start + page * PAGE_SIZE).is_some());
+        }
+        assert!(memory.translate(start - 1_u64).is_none());
+        assert!(memory.translate(start + 3 * PAGE_SIZE).is_none());
+
+        unsafe { memory.vfree(start) }.unwrap();
+        assert!(memory.translate(start).is_none());
+        assert!(memory.regions().iter().all(|region| region.start != start));
+    }
+}
@@ -0,0 +1,278 @@
+use core::fmt;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PageTableIndex};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::PHYSICAL_MEMORY_OFFSET;
+
+/// The flags changed by the processor, left out when comparing mappings.
+fn ignored_flags() -> PageTableFlags {
+    PageTableFlags::ACCESSED | PageTableFlags::DIRTY
+}
+
+/// Size of the memory mapped by an entry of a table of the given level.
+pub(super) const fn entry_size(level: u8) -> u64 {
+    1 << (12 + 9 * (level as u64 - 1))
+}
+
+/// An entry read during a page walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// 4 for the P4 table down to 1 for a P1 table.
+    pub level: u8,
+    pub index: PageTableIndex,
+    pub flags: PageTableFlags,
+    /// The next table, or the frame for the last step.
+    pub addr: PhysAddr,
+}
+
+impl fmt::Display for WalkStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "P{}[{:>3}] {:#014x} {:?}",
+            self.level,
+            u16::from(self.index),
+            self.addr.as_u64(),
+            self.flags
+        )
+    }
+}
+
+/// The entries read to translate an address, from the P4 table down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageWalk {
+    pub addr: VirtAddr,
+    steps: [Option<WalkStep>; 4],
+    /// The physical address and the size of the page, if it is mapped.
+    pub mapping: Option<(PhysAddr, u64)>,
+}
+
+impl PageWalk {
+    pub fn steps(&self) -> impl Iterator<Item = &WalkStep> {
+        self.steps.iter().flatten()
+    }
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:#x}:", self.addr.as_u64())?;
+        for step in self.steps() {
+            writeln!(f, "  {step}")?;
+        }
+        match self.mapping {
+            Some((addr, size)) => write!(f, "  => {:#x} ({} KiB page)", addr.as_u64(), size / 1024),
+            None => write!(f, "  => not mapped"),
+        }
+    }
+}
+
+/// The table at `frame` in the mapping of the complete physical memory.
+fn table(offset: VirtAddr, frame: PhysAddr) -> &'static PageTable {
+    unsafe {
+        // SAFETY: the complete physical memory is mapped at `offset` and the
+        // frame holds a page table.
+        &*(offset + frame.as_u64()).as_ptr::<PageTable>()
+    }
+}
+
+/// Walks the active page tables from `Cr3` to translate `addr`, stopping at
+/// the first entry that is not present or that maps a huge page.
+///
+/// Returns `None` before `memory::init`.
+#[must_use]
+pub fn page_walk(addr: VirtAddr) -> Option<PageWalk> {
+    let offset = *PHYSICAL_MEMORY_OFFSET.r#try()?;
+    let mut walk = PageWalk {
+        addr,
+        steps: [None; 4],
+        mapping: None,
+    };
+
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table_addr = Cr3::read().0.start_address();
+    for (step, (level, index)) in walk.steps.iter_mut().zip((1..=4).rev().zip(indexes)) {
+        let entry = &table(offset, table_addr)[index];
+        *step = Some(WalkStep {
+            level,
+            index,
+            flags: entry.flags(),
+            addr: entry.addr(),
+        });
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return Some(walk);
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let size = entry_size(level);
+            walk.mapping = Some((entry.addr() + (addr.as_u64() & (size - 1)), size));
+            return Some(walk);
+        }
+        table_addr = entry.addr();
+    }
+    Some(walk)
+}
+
+/// Consecutive pages mapped to consecutive frames with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Adds `next` to the range if it follows it, returns whether it did.
+    fn extend(&mut self, next: &MappedRange) -> bool {
+        let follows = self.start + self.size == next.start
+            && self.phys + self.size == next.phys
+            && self.flags == next.flags;
+        if follows {
+            self.size += next.size;
+        }
+        follows
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#014x}..{:#014x} -> {:#014x} {:?}",
+            self.start.as_u64(),
+            (self.start + self.size).as_u64(),
+            self.phys.as_u64(),
+            self.flags
+        )
+    }
+}
+
+/// Calls `f` on the present mappings of the active page tables, by address,
+/// with the consecutive ones coalesced.
+///
+/// Does nothing before `memory::init`.
+pub fn for_each_mapping(mut f: impl FnMut(&MappedRange)) {
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.r#try() else {
+        return;
+    };
+    let mut current: Option<MappedRange> = None;
+    let p4 = table(offset, Cr3::read().0.start_address());
+    visit_table(offset, p4, 4, 0, &mut |next| {
+        if current.as_mut().is_some_and(|range| range.extend(&next)) {
+            return;
+        }
+        if let Some(range) = current.replace(next) {
+            f(&range);
+        }
+    });
+    if let Some(range) = current {
+        f(&range);
+    }
+}
+
+fn visit_table(
+    offset: VirtAddr,
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    f: &mut impl FnMut(MappedRange),
+) {
+    for (index, entry) in (0_u64..).zip(table.iter()) {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base | (index * entry_size(level));
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            f(leaf(entry, start, entry_size(level)));
+        } else {
+            visit_table(
+                offset,
+                self::table(offset, entry.addr()),
+                level - 1,
+                start,
+                f,
+            );
+        }
+    }
+}
+
+fn leaf(entry: &PageTableEntry, start: u64, size: u64) -> MappedRange {
+    MappedRange {
+        // The upper bits are the sign extension of the P4 index.
+        start: VirtAddr::new_truncate(start),
+        phys: entry.addr(),
+        size,
+        flags: entry.flags() - ignored_flags(),
+    }
+}
+
+/// Writes the present mappings of the active page tables, coalesced.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails.
+pub fn dump_mappings(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut result = Ok(());
+    for_each_mapping(|range| {
+        if result.is_ok() {
+            result = writeln!(out, "{range}");
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod walk_tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::allocator::HEAP_START;
+    use crate::memory::vmalloc::{VMALLOC_SIZE, VMALLOC_START};
+    use crate::memory::MEMORY_MANAGER;
+    use crate::{assert, assert_eq};
+
+    #[test_case]
+    fn test_walk_heap_page() {
+        let value = Box::new(42_u64);
+        let addr = VirtAddr::from_ptr(&raw const *value);
+        let walk = page_walk(addr).unwrap();
+
+        assert_eq!(walk.steps().count(), 4);
+        assert!(walk
+            .steps()
+            .all(|step| step.flags.contains(PageTableFlags::PRESENT)));
+        let translated = MEMORY_MANAGER.lock().as_ref().unwrap().translate(addr);
+        assert_eq!(walk.mapping, Some((translated.unwrap(), 4096)));
+    }
+
+    #[test_case]
+    fn test_walk_unmapped_address() {
+        // The end of the vmalloc window is never reached.
+        let walk = page_walk(VirtAddr::new(VMALLOC_START + VMALLOC_SIZE - 4096)).unwrap();
+        assert_eq!(walk.mapping, None);
+        let last = walk.steps().last().unwrap();
+        assert!(!last.flags.contains(PageTableFlags::PRESENT));
+    }
+
+    #[test_case]
+    fn test_heap_in_mappings() {
+        let mut heap = None;
+        for_each_mapping(|range| {
+            let heap_start = VirtAddr::new(HEAP_START as u64);
+            if range.start <= heap_start && heap_start < range.start + range.size {
+                heap = Some(*range);
+            }
+        });
+        let heap = heap.unwrap();
+        assert!(heap
+            .flags
+            .contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    }
+}
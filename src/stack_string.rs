@@ -112,7 +112,10 @@ impl<const CAPACITY: usize> core::ops::IndexMut<usize> for StackString<CAPACITY>
 
 #[cfg(test)]
 mod stack_string_test {
+    use alloc::vec::Vec;
+
     use crate::assert_eq;
+    use crate::test_utils::prop::{check, Strings, Vecs};
 
     use super::*;
 
@@ -142,4 +145,38 @@ mod stack_string_test {
             Err::<usize, StackStringError>(StackStringError::ExceedCapacity(5))
         );
     }
+
+    /// An empty string pops a character, any other is pushed.
+    #[test_case]
+    fn test_stack_string_against_model() {
+        let operations = Vecs {
+            element: Strings {
+                max_len: 4,
+                alphabet: "ab☺é",
+            },
+            max_len: 12,
+        };
+        check(&operations, |operations| {
+            let mut s = StackString::<8>::default();
+            let mut model = Vec::new();
+
+            for operation in operations {
+                if operation.is_empty() {
+                    assert_eq!(s.pop(), model.pop());
+                } else {
+                    let len = operation.chars().count();
+                    let written = len.min(8 - model.len());
+                    model.extend(operation.chars().take(written));
+                    let expected = if written == len {
+                        Ok(written)
+                    } else {
+                        Err(StackStringError::ExceedCapacity(written))
+                    };
+                    assert_eq!(s.push_str(operation), expected);
+                }
+                assert_eq!(s.len(), model.len());
+                crate::assert!(s.get_data() == model.as_slice());
+            }
+        });
+    }
 }
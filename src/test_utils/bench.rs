@@ -0,0 +1,175 @@
+use core::arch::x86_64::_rdtsc;
+use core::hint::black_box;
+
+use crate::serial::Green;
+use crate::test_utils::Testable;
+use crate::{serial_print, serial_println};
+
+/// Runs of the closure that are not measured, to warm up the caches.
+pub const WARM_UP_RUNS: usize = 16;
+/// Number of measures, each one times `iterations` runs of the closure.
+pub const SAMPLES: usize = 51;
+pub const DEFAULT_ITERATIONS: u64 = 100;
+
+/// A benchmark registered with `bench_case!`.
+pub struct BenchCase {
+    pub name: &'static str,
+    pub function: fn(&mut Bencher),
+}
+
+impl Testable for BenchCase {
+    fn run(&self) {
+        serial_print!("bench {} ... ", self.name);
+        let mut bencher = Bencher::new();
+        (self.function)(&mut bencher);
+        match bencher.stats() {
+            Some(stats) => serial_println!("{} {}", Green("ok"), stats),
+            None => serial_println!("{} (nothing measured)", Green("ok")),
+        }
+    }
+}
+
+/// Minimum, median and maximum of the samples, in cycles per iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchStats {
+    pub min: u64,
+    pub median: u64,
+    pub max: u64,
+}
+
+impl core::fmt::Display for BenchStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "min {} / median {} / max {} cycles/iter",
+            self.min, self.median, self.max
+        )
+    }
+}
+
+pub struct Bencher {
+    iterations: u64,
+    samples: [u64; SAMPLES],
+    measured: bool,
+}
+
+impl Default for Bencher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bencher {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            iterations: DEFAULT_ITERATIONS,
+            samples: [0; SAMPLES],
+            measured: false,
+        }
+    }
+
+    /// Sets the number of runs timed together in one sample,
+    /// expensive closures can use a lower count.
+    pub fn set_iterations(&mut self, iterations: u64) {
+        self.iterations = iterations.max(1);
+    }
+
+    /// Times the given closure, the returned value is passed to `black_box`
+    /// so the work is not optimized away.
+    pub fn measure<R>(&mut self, mut f: impl FnMut() -> R) {
+        for _ in 0..WARM_UP_RUNS {
+            black_box(f());
+        }
+
+        for sample in &mut self.samples {
+            let start = read_tsc();
+            for _ in 0..self.iterations {
+                black_box(f());
+            }
+            let end = read_tsc();
+            *sample = end.saturating_sub(start) / self.iterations;
+        }
+
+        self.measured = true;
+    }
+
+    /// Returns `None` if `measure` was never called.
+    #[must_use]
+    pub fn stats(&self) -> Option<BenchStats> {
+        if !self.measured {
+            return None;
+        }
+
+        let mut sorted = self.samples;
+        sorted.sort_unstable();
+        Some(BenchStats {
+            min: sorted[0],
+            median: sorted[SAMPLES / 2],
+            max: sorted[SAMPLES - 1],
+        })
+    }
+}
+
+fn read_tsc() -> u64 {
+    unsafe {
+        // SAFETY: the time stamp counter is available on every x86_64 CPU.
+        _rdtsc()
+    }
+}
+
+/// Declares a benchmark run by `test_runner` alongside the `#[test_case]`s.
+///
+/// ```ignore
+/// bench_case! {
+///     fn push_char(bencher) {
+///         let mut s = StackString::<8>::default();
+///         bencher.measure(|| { s.clear(); s.push('a') });
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! bench_case {
+    ($(#[$meta:meta])* fn $name:ident($bencher:ident) $body:block) => {
+        $(#[$meta])*
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::test_utils::bench::BenchCase = $crate::test_utils::bench::BenchCase {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            function: {
+                fn $name($bencher: &mut $crate::test_utils::bench::Bencher) $body
+                $name
+            },
+        };
+    };
+}
+
+#[cfg(test)]
+mod bench_tests {
+    use crate::{assert, assert_eq};
+
+    use super::*;
+
+    #[test_case]
+    fn test_stats_ordering() {
+        let mut bencher = Bencher::new();
+        assert_eq!(bencher.stats(), None::<BenchStats>);
+
+        bencher.set_iterations(10);
+        let mut counter = 0_u64;
+        bencher.measure(|| {
+            counter += 1;
+            counter
+        });
+
+        let stats = bencher.stats().unwrap();
+        assert!(stats.min <= stats.median && stats.median <= stats.max);
+        assert_eq!(counter, WARM_UP_RUNS as u64 + SAMPLES as u64 * 10);
+    }
+
+    bench_case! {
+        fn bench_empty_closure(bencher) {
+            bencher.measure(|| ());
+        }
+    }
+}
@@ -0,0 +1,463 @@
+pub mod bench;
+pub mod prop;
+
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::panic::PanicInfo;
+
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::allocator::{self, debug::LiveAllocations};
+use crate::hlt_loop;
+use crate::memory::MEMORY_MANAGER;
+use crate::serial::{Green, Red};
+use crate::{exit_qemu, QemuExitCode};
+use crate::{serial_print, serial_println};
+
+lazy_static! {
+    pub static ref TEST_STATE: Mutex<TestState> = Mutex::new(TestState::default());
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("{}", Red("FAILED"));
+    serial_println!("Error: {}\n", info);
+    if let Some(seed) = prop::active_seed() {
+        serial_println!(
+            "Property seed: {} (rebuild with PROP_SEED={} to replay).",
+            seed,
+            seed
+        );
+    }
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        serial_print!("test {} ... ", core::any::type_name::<T>());
+        self();
+        if TEST_STATE.lock().failed {
+            serial_println!("{}", Red("FAILED"));
+            serial_println!("{}", TEST_STATE.lock().error_message);
+            TEST_STATE.lock().clear();
+        } else {
+            serial_println!("{}", Green("ok"));
+        }
+    }
+}
+
+/// The leaked allocations listed after the tests.
+const MAX_REPORTED_LEAKS: usize = 32;
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    let first_test_allocation = allocator::live_allocations().map(LiveAllocations::next_id);
+    for test in tests {
+        test.run();
+    }
+    if let (Some(live), Some(since)) = (allocator::live_allocations(), first_test_allocation) {
+        report_leaks(live, since);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Lists the allocations made by the tests that are still live, lazy statics
+/// initialized by a test show up too.
+fn report_leaks(live: &LiveAllocations, since: u64) {
+    let mut count = 0;
+    live.for_each_since(since, |_| count += 1);
+    if count == 0 {
+        return;
+    }
+
+    serial_println!("{} allocations made by the tests are still live:", count);
+    let mut reported = 0;
+    live.for_each_since(since, |allocation| {
+        if reported < MAX_REPORTED_LEAKS {
+            serial_println!("  {}", allocation);
+            reported += 1;
+        }
+    });
+    if count > MAX_REPORTED_LEAKS {
+        serial_println!("  ...");
+    }
+}
+
+/// A statically allocated memory region, used to give private memory
+/// to an allocator under test.
+#[repr(C, align(4096))]
+pub struct Arena<const SIZE: usize>(UnsafeCell<[u8; SIZE]>);
+
+unsafe impl<const SIZE: usize> Sync for Arena<SIZE> {}
+
+impl<const SIZE: usize> Arena<SIZE> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new([0; SIZE]))
+    }
+
+    #[must_use]
+    pub fn start(&self) -> usize {
+        self.0.get() as usize
+    }
+
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        SIZE
+    }
+
+    #[must_use]
+    pub fn end(&self) -> usize {
+        self.start() + SIZE
+    }
+}
+
+impl<const SIZE: usize> Default for Arena<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps `size` bytes of pages accessible to ring 3 code in the vmalloc
+/// window, `USER_ACCESSIBLE` is added to `flags`.
+///
+/// # Panics
+///
+/// Panics if the memory manager is not initialized or if the pages cannot be
+/// mapped.
+#[must_use]
+pub fn map_user_pages(size: usize, flags: PageTableFlags) -> VirtAddr {
+    let mut memory = MEMORY_MANAGER.lock();
+    let memory = memory.as_mut().expect("memory not initialized");
+    let start = memory
+        .reserve("user", size, 1)
+        .expect("no user region left");
+    memory
+        .map_range(start, size, flags | PageTableFlags::USER_ACCESSIBLE)
+        .expect("user pages not mapped");
+    start
+}
+
+#[derive(Default)]
+pub struct TestState {
+    pub failed: bool,
+    pub error_message: String,
+}
+
+impl TestState {
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Marks the current test as failed, the message is prefixed by the
+    /// location of the failing assertion.
+    pub fn fail(&mut self, file: &str, line: u32, args: fmt::Arguments) {
+        use core::fmt::Write;
+
+        self.failed = true;
+        let _ = writeln!(self, "{file}:{line}");
+        let _ = self.write_fmt(args);
+    }
+}
+
+impl fmt::Write for TestState {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Do not abort the whole run if the heap is exhausted,
+        // the message is only truncated.
+        if self.error_message.try_reserve(s.len()).is_ok() {
+            self.error_message.push_str(s);
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! assert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!(" left = {:#?}\nright = {:#?}\n", left, right),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!(
+                            " left = {:#?}\nright = {:#?}\n{}\n",
+                            left, right, format_args!($($arg)+)
+                        ),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! assert_ne {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left == *right {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!("Values are equal.\n left = {:#?}\nright = {:#?}\n", left, right),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left == *right {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!(
+                            "Values are equal.\n left = {:#?}\nright = {:#?}\n{}\n",
+                            left, right, format_args!($($arg)+)
+                        ),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! assert_matches {
+    ($value:expr, $pattern:pat $(if $guard:expr)? $(,)?) => {
+        match &$value {
+            $pattern $(if $guard)? => {}
+            value => {
+                $crate::test_utils::TEST_STATE.lock().fail(
+                    file!(),
+                    line!(),
+                    format_args!(
+                        "value = {:#?}\ndoes not match `{}`\n",
+                        value, stringify!($pattern $(if $guard)?)
+                    ),
+                );
+                return;
+            }
+        }
+    };
+    ($value:expr, $pattern:pat $(if $guard:expr)?, $($arg:tt)+) => {
+        match &$value {
+            $pattern $(if $guard)? => {}
+            value => {
+                $crate::test_utils::TEST_STATE.lock().fail(
+                    file!(),
+                    line!(),
+                    format_args!(
+                        "value = {:#?}\ndoes not match `{}`\n{}\n",
+                        value, stringify!($pattern $(if $guard)?), format_args!($($arg)+)
+                    ),
+                );
+                return;
+            }
+        }
+    };
+}
+
+/// Default tolerance of `assert_approx_eq!`.
+pub const DEFAULT_EPSILON: f64 = 1e-6;
+
+/// Compares two floats, the values are converted to `f64`.
+#[macro_export]
+macro_rules! assert_approx_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::assert_approx_eq!($left, $right, $crate::test_utils::DEFAULT_EPSILON)
+    };
+    ($left:expr, $right:expr, $epsilon:expr $(,)?) => {
+        match (f64::from($left), f64::from($right), f64::from($epsilon)) {
+            (left, right, epsilon) => {
+                let difference = (left - right).abs();
+                if difference.is_nan() || difference > epsilon {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!(
+                            " left = {:?}\nright = {:?}\ndifference exceeds {:?}\n",
+                            left, right, epsilon
+                        ),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+    ($left:expr, $right:expr, $epsilon:expr, $($arg:tt)+) => {
+        match (f64::from($left), f64::from($right), f64::from($epsilon)) {
+            (left, right, epsilon) => {
+                let difference = (left - right).abs();
+                if difference.is_nan() || difference > epsilon {
+                    $crate::test_utils::TEST_STATE.lock().fail(
+                        file!(),
+                        line!(),
+                        format_args!(
+                            " left = {:?}\nright = {:?}\ndifference exceeds {:?}\n{}\n",
+                            left, right, epsilon, format_args!($($arg)+)
+                        ),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! assert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::test_utils::TEST_STATE.lock().fail(
+                file!(),
+                line!(),
+                format_args!("{}", "Assertion failed."),
+            );
+            return;
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::test_utils::TEST_STATE.lock().fail(
+                file!(),
+                line!(),
+                format_args!("{}", format_args!($($arg)+)),
+            );
+            return;
+        }
+    };
+}
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+/// Entry point for `cargo test`.
+#[cfg(test)]
+pub fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    crate::init(boot_info);
+    crate::test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
+
+#[cfg(test)]
+mod assert_tests {
+    use super::TEST_STATE;
+
+    #[test_case]
+    fn failing_assert_test() {
+        assert!(false);
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_with_message_test() {
+        let the_message = "This message should be printed.";
+        assert!(false, "{the_message}");
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_eq_test() {
+        assert_eq!(0, 1);
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_eq_with_message_test() {
+        let the_message = "This message should be printed.";
+        assert_eq!(2, 3, "{the_message}");
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_ne_test() {
+        assert_ne!(4, 4);
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_matches_test() {
+        assert_matches!(Some(3), Some(x) if *x > 5);
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn failing_assert_approx_eq_test() {
+        assert_approx_eq!(0.1_f32, 0.2_f32);
+        panic!("Should not panics.");
+    }
+
+    #[test_case]
+    fn passing_asserts_test() {
+        assert_ne!(1, 2);
+        assert_matches!(Ok::<u8, ()>(1), Ok(1));
+        assert_approx_eq!(0.1 + 0.2, 0.3);
+        assert_approx_eq!(1.0, 1.5, 0.5, "within the given epsilon");
+        assert!(!TEST_STATE.lock().failed);
+    }
+
+    #[test_case]
+    fn failure_location_and_long_message_test() {
+        #[derive(Debug, PartialEq)]
+        struct Big([usize; 64]);
+
+        fn failing() {
+            assert_eq!(Big([0; 64]), Big([1; 64]));
+        }
+
+        failing();
+        let (failed, message) = {
+            let mut test_state = TEST_STATE.lock();
+            let result = (test_state.failed, test_state.error_message.clone());
+            test_state.clear();
+            result
+        };
+        assert!(failed);
+        assert!(message.starts_with(file!()));
+        // Both values are fully dumped, nothing is truncated.
+        assert!(message.len() > 2 * 64 * 4);
+        assert!(message.trim_end().ends_with('}'));
+    }
+
+    #[test_case]
+    fn empty_test() {}
+}
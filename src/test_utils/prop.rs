@@ -0,0 +1,519 @@
+//! Property-based testing: a property is checked on random inputs and the
+//! failing input is shrunk to a minimal one before being reported.
+//!
+//! A property fails through the assertion macros of `test_utils`, the seed of
+//! the run is printed with the failure. Build with `PROP_SEED=<seed>` to
+//! replay a run.
+
+// The values are generated as `u64` and converted back to the tested type.
+#![allow(clippy::cast_possible_truncation, clippy::cast_lossless)]
+
+use alloc::alloc::Layout;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::test_utils::TEST_STATE;
+
+pub const DEFAULT_CASES: usize = 100;
+/// Upper bound of the shrinking candidates tried after a failure.
+pub const MAX_SHRINK_STEPS: usize = 1000;
+
+/// Seed of the property being checked, printed by `test_panic_handler`.
+static ACTIVE_SEED: AtomicU64 = AtomicU64::new(0);
+static SEED_IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Returns the seed of the property being checked, if any.
+pub fn active_seed() -> Option<u64> {
+    SEED_IS_ACTIVE
+        .load(Ordering::Relaxed)
+        .then(|| ACTIVE_SEED.load(Ordering::Relaxed))
+}
+
+/// A `SplitMix64` pseudo random number generator.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Uses `PROP_SEED` from the build environment, or else the time stamp counter.
+    #[must_use]
+    pub fn from_env_or_tsc() -> Self {
+        Self::new(default_seed())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..bound`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bound` is 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "empty range");
+        // Multiply-shift, the bias is negligible for testing.
+        ((u128::from(self.next_u64()) * u128::from(bound)) >> 64) as u64
+    }
+
+    /// Returns a number in the given inclusive range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty.
+    pub fn in_range(&mut self, range: RangeInclusive<u64>) -> u64 {
+        let (start, end) = range.into_inner();
+        assert!(start <= end, "empty range");
+        match (end - start).checked_add(1) {
+            Some(len) => start + self.below(len),
+            None => self.next_u64(),
+        }
+    }
+
+    pub fn next_bool(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+
+    /// Shuffles the slice in place (Fisher-Yates).
+    pub fn shuffle<T>(&mut self, slice: &mut [T]) {
+        for i in (1..slice.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            slice.swap(i, j);
+        }
+    }
+}
+
+fn default_seed() -> u64 {
+    option_env!("PROP_SEED")
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| unsafe {
+            // SAFETY: the time stamp counter is available on every x86_64 CPU.
+            core::arch::x86_64::_rdtsc()
+        })
+}
+
+/// Produces random values and simpler versions of a failing value.
+pub trait Generator {
+    type Value: fmt::Debug + Clone;
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value;
+
+    /// Returns simpler candidates than `value`, the most aggressive first.
+    fn shrink(&self, _value: &Self::Value) -> Vec<Self::Value> {
+        Vec::new()
+    }
+}
+
+/// Candidates between `target` and `value`: the target first, then halfway
+/// values getting closer to `value`.
+fn shrink_toward(target: u64, value: u64) -> Vec<u64> {
+    let mut candidates = Vec::new();
+    let mut distance = value - target;
+    while distance > 0 {
+        candidates.push(value - distance);
+        distance /= 2;
+    }
+    candidates
+}
+
+macro_rules! impl_range_generator {
+    ($($t:ty),*) => {$(
+        impl Generator for RangeInclusive<$t> {
+            type Value = $t;
+
+            fn generate(&self, rng: &mut Rng) -> $t {
+                rng.in_range(*self.start() as u64..=*self.end() as u64) as $t
+            }
+
+            fn shrink(&self, value: &$t) -> Vec<$t> {
+                shrink_toward(*self.start() as u64, *value as u64)
+                    .into_iter()
+                    .map(|v| v as $t)
+                    .collect()
+            }
+        }
+    )*};
+}
+
+impl_range_generator!(u8, u16, u32, u64, usize);
+
+/// Generates layouts with a size in `1..=max_size` and a power of two
+/// alignment up to `max_align`.
+#[derive(Debug, Clone, Copy)]
+pub struct Layouts {
+    pub max_size: usize,
+    pub max_align: usize,
+}
+
+impl Generator for Layouts {
+    type Value = Layout;
+
+    fn generate(&self, rng: &mut Rng) -> Layout {
+        let size = rng.in_range(1..=self.max_size as u64) as usize;
+        let max_shift = u64::from(self.max_align.max(1).ilog2());
+        let align = 1 << rng.in_range(0..=max_shift);
+        Layout::from_size_align(size, align).expect("invalid layout")
+    }
+
+    fn shrink(&self, value: &Layout) -> Vec<Layout> {
+        let mut candidates = Vec::new();
+        let mut align = 1;
+        while align < value.align() {
+            candidates.extend(Layout::from_size_align(value.size(), align).ok());
+            align *= 2;
+        }
+        for size in shrink_toward(1, value.size() as u64) {
+            candidates.extend(Layout::from_size_align(size as usize, value.align()).ok());
+        }
+        candidates
+    }
+}
+
+/// Generates strings of up to `max_len` characters taken from `alphabet`.
+#[derive(Debug, Clone, Copy)]
+pub struct Strings {
+    pub max_len: usize,
+    pub alphabet: &'static str,
+}
+
+impl Generator for Strings {
+    type Value = String;
+
+    fn generate(&self, rng: &mut Rng) -> String {
+        let len = rng.in_range(0..=self.max_len as u64);
+        let alphabet_len = self.alphabet.chars().count() as u64;
+        (0..len)
+            .filter_map(|_| self.alphabet.chars().nth(rng.below(alphabet_len) as usize))
+            .collect()
+    }
+
+    fn shrink(&self, value: &String) -> Vec<String> {
+        let chars: Vec<char> = value.chars().collect();
+        let mut candidates: Vec<String> = shrink_removals(&chars)
+            .into_iter()
+            .map(|chars| chars.into_iter().collect())
+            .collect();
+
+        // Replace each character by the simplest one.
+        if let Some(simplest) = self.alphabet.chars().next() {
+            for i in 0..chars.len() {
+                if chars[i] != simplest {
+                    let mut simpler = chars.clone();
+                    simpler[i] = simplest;
+                    candidates.push(simpler.into_iter().collect());
+                }
+            }
+        }
+        candidates
+    }
+}
+
+/// Generates vectors of up to `max_len` elements.
+#[derive(Debug, Clone, Copy)]
+pub struct Vecs<G> {
+    pub element: G,
+    pub max_len: usize,
+}
+
+impl<G: Generator> Generator for Vecs<G> {
+    type Value = Vec<G::Value>;
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value {
+        let len = rng.in_range(0..=self.max_len as u64);
+        (0..len).map(|_| self.element.generate(rng)).collect()
+    }
+
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value> {
+        let mut candidates = shrink_removals(value);
+        for (i, element) in value.iter().enumerate() {
+            for simpler in self.element.shrink(element) {
+                let mut candidate = value.clone();
+                candidate[i] = simpler;
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
+}
+
+/// Candidates with elements removed: halves first, then single elements.
+fn shrink_removals<T: Clone>(values: &[T]) -> Vec<Vec<T>> {
+    let mut candidates = Vec::new();
+    let mut chunk = values.len() / 2;
+    while chunk > 0 {
+        for start in (0..values.len()).step_by(chunk) {
+            let end = (start + chunk).min(values.len());
+            candidates.push([&values[..start], &values[end..]].concat());
+        }
+        chunk /= 2;
+    }
+    if values.len() == 1 {
+        candidates.push(Vec::new());
+    }
+    candidates
+}
+
+impl<A: Generator, B: Generator> Generator for (A, B) {
+    type Value = (A::Value, B::Value);
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value {
+        (self.0.generate(rng), self.1.generate(rng))
+    }
+
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value> {
+        let firsts = self.0.shrink(&value.0).into_iter();
+        let seconds = self.1.shrink(&value.1).into_iter();
+        firsts
+            .map(|a| (a, value.1.clone()))
+            .chain(seconds.map(|b| (value.0.clone(), b)))
+            .collect()
+    }
+}
+
+impl<A: Generator, B: Generator, C: Generator> Generator for (A, B, C) {
+    type Value = (A::Value, B::Value, C::Value);
+
+    fn generate(&self, rng: &mut Rng) -> Self::Value {
+        (
+            self.0.generate(rng),
+            self.1.generate(rng),
+            self.2.generate(rng),
+        )
+    }
+
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value> {
+        let (a, b, c) = value;
+        let firsts = self.0.shrink(a).into_iter();
+        let seconds = self.1.shrink(b).into_iter();
+        let thirds = self.2.shrink(c).into_iter();
+        firsts
+            .map(|x| (x, b.clone(), c.clone()))
+            .chain(seconds.map(|x| (a.clone(), x, c.clone())))
+            .chain(thirds.map(|x| (a.clone(), b.clone(), x)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub cases: usize,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cases: DEFAULT_CASES,
+            seed: default_seed(),
+        }
+    }
+}
+
+/// Checks `property` against `DEFAULT_CASES` random values.
+///
+/// The property fails with the assertion macros of `test_utils`. On failure
+/// the test is marked as failed with the shrunk input and the seed, the rest
+/// of the calling test still runs.
+#[track_caller]
+pub fn check<G: Generator>(generator: &G, property: impl Fn(&G::Value)) {
+    Config::default().check(generator, property);
+}
+
+impl Config {
+    /// See `check`.
+    #[track_caller]
+    pub fn check<G: Generator>(&self, generator: &G, property: impl Fn(&G::Value)) {
+        let location = Location::caller();
+        // A failure of the test before the check is not one of the property.
+        let earlier = core::mem::take(&mut *TEST_STATE.lock());
+        let mut rng = Rng::new(self.seed);
+        ACTIVE_SEED.store(self.seed, Ordering::Relaxed);
+        SEED_IS_ACTIVE.store(true, Ordering::Relaxed);
+
+        for case in 0..self.cases {
+            let value = generator.generate(&mut rng);
+            if let Some(message) = run_property(&property, &value) {
+                let (minimal, message, steps) = shrink(generator, &property, value, message);
+                TEST_STATE.lock().fail(
+                    location.file(),
+                    location.line(),
+                    format_args!(
+                        "Property failed on case {case} with seed {} \
+                         (rebuild with PROP_SEED={} to replay).\n\
+                         Minimal input after {steps} shrinking steps: {minimal:#?}\n{message}",
+                        self.seed, self.seed
+                    ),
+                );
+                break;
+            }
+        }
+
+        SEED_IS_ACTIVE.store(false, Ordering::Relaxed);
+        if earlier.failed {
+            use fmt::Write as _;
+
+            let mut test_state = TEST_STATE.lock();
+            let later = core::mem::replace(&mut *test_state, earlier);
+            if later.failed {
+                let _ = write!(test_state, "\n{}", later.error_message);
+            }
+        }
+    }
+}
+
+/// Returns the failure message if the property does not hold.
+fn run_property<T>(property: &impl Fn(&T), value: &T) -> Option<String> {
+    property(value);
+    let mut test_state = TEST_STATE.lock();
+    if test_state.failed {
+        let message = core::mem::take(&mut test_state.error_message);
+        test_state.clear();
+        Some(message)
+    } else {
+        None
+    }
+}
+
+fn shrink<G: Generator>(
+    generator: &G,
+    property: &impl Fn(&G::Value),
+    mut value: G::Value,
+    mut message: String,
+) -> (G::Value, String, usize) {
+    let mut steps = 0;
+    'outer: while steps < MAX_SHRINK_STEPS {
+        for candidate in generator.shrink(&value) {
+            steps += 1;
+            if let Some(candidate_message) = run_property(property, &candidate) {
+                value = candidate;
+                message = candidate_message;
+                continue 'outer;
+            }
+            if steps >= MAX_SHRINK_STEPS {
+                break 'outer;
+            }
+        }
+        break;
+    }
+    (value, message, steps)
+}
+
+#[cfg(test)]
+mod prop_tests {
+    use super::*;
+    use crate::{assert, assert_eq, assert_ne};
+
+    #[test_case]
+    fn test_rng_is_deterministic() {
+        /// The values drawn by the generators from a `seed`.
+        fn sequence(seed: u64) -> Vec<u64> {
+            let mut rng = Rng::new(seed);
+            let mut values: Vec<u64> = (0..100).map(|_| rng.next_u64()).collect();
+            values.extend((0..100).map(|_| rng.below(1000)));
+            values.extend((0..100).map(|_| rng.in_range(3..=7)));
+            values.extend((0..100).map(|_| u64::from(rng.next_bool())));
+            let mut shuffled: Vec<u64> = (0..100).collect();
+            rng.shuffle(&mut shuffled);
+            values.extend(shuffled);
+            values
+        }
+
+        let values = sequence(42);
+        assert_eq!(values, sequence(42));
+        assert!(values[200..300].iter().all(|value| (3..=7).contains(value)));
+        assert_ne!(values, sequence(43));
+    }
+
+    #[test_case]
+    fn test_shrinks_to_minimal_value() {
+        let config = Config {
+            cases: 1000,
+            seed: 7,
+        };
+        config.check(&(0..=1000_usize), |&x| {
+            assert!(x < 100);
+        });
+
+        let message = {
+            let mut test_state = TEST_STATE.lock();
+            let message = core::mem::take(&mut test_state.error_message);
+            test_state.clear();
+            message
+        };
+        assert!(message.contains("seed 7"));
+        assert!(message.contains("input after"));
+        assert!(message.contains(": 100\n"));
+    }
+
+    #[test_case]
+    fn test_shrinks_vec_to_single_element() {
+        let config = Config {
+            cases: 200,
+            seed: 1,
+        };
+        config.check(
+            &Vecs {
+                element: 0..=50_u8,
+                max_len: 20,
+            },
+            |values| {
+                assert!(!values.contains(&42));
+            },
+        );
+
+        let message = core::mem::take(&mut TEST_STATE.lock().error_message);
+        TEST_STATE.lock().clear();
+        assert!(message.contains("[\n    42,\n]"));
+    }
+
+    #[test_case]
+    fn test_generators_respect_bounds() {
+        check(
+            &(
+                Layouts {
+                    max_size: 512,
+                    max_align: 64,
+                },
+                Strings {
+                    max_len: 8,
+                    alphabet: "aé☺",
+                },
+            ),
+            |(layout, s)| {
+                assert!((1..=512).contains(&layout.size()));
+                assert!(layout.align() <= 64);
+                assert!(s.chars().count() <= 8);
+                assert!(s.chars().all(|c| "aé☺".contains(c)));
+            },
+        );
+    }
+
+    #[test_case]
+    fn test_earlier_failure_kept() {
+        TEST_STATE
+            .lock()
+            .fail("earlier.rs", 1, format_args!("earlier failure"));
+        check(&(0..=10_u8), |&x| {
+            assert!(x <= 10);
+        });
+
+        let earlier = core::mem::take(&mut *TEST_STATE.lock());
+        assert!(earlier.failed);
+        assert!(earlier.error_message.contains("earlier failure"));
+        assert!(!earlier.error_message.contains("Property failed"));
+    }
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+//! Exhausts the heap and checks that the allocation error handler panics
+//! after the failure, with the report it writes on serial.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+
+use os::allocator::{report_alloc_failure, HEAP_MAX_SIZE};
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("alloc_error::heap_exhaustion...\t");
+    let buffer = Vec::<u8>::with_capacity(HEAP_MAX_SIZE);
+    serial_println!("[allocation of {} bytes succeeded]", buffer.capacity());
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let expected = Layout::from_size_align(HEAP_MAX_SIZE, 1).unwrap();
+    // The heap is not full, only the huge allocation failed.
+    let mut report = String::new();
+    let _ = report_alloc_failure(expected, &mut report);
+    let first_line = format!("allocation of {HEAP_MAX_SIZE} bytes aligned to 1 failed\n");
+    if format!("{}", info.message()) == format!("allocation error: {expected:?}")
+        && report.starts_with(&first_line)
+        && report.contains("bytes in use")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    os::hlt_loop();
+}
@@ -0,0 +1,236 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+use core::ptr;
+
+use os::allocator::buddy::BuddyAllocator;
+use os::allocator::bump::BumpAllocator;
+use os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use os::allocator::linked_list::{BestFit, FirstFit, LinkedListAllocator, NextFit, Placement};
+use os::allocator::{HeapAllocator, Locked};
+use os::test_utils::bench::Bencher;
+use os::test_utils::prop::{Generator, Rng};
+use os::test_utils::{test_panic_handler, Arena};
+use os::{bench_case, serial_print};
+
+/// The benchmarks run one after the other, they all reuse this memory.
+static ARENA: Arena<{ 64 * 1024 }> = Arena::new();
+
+const SMALL: Layout = Layout::new::<u64>();
+const SIZES: [usize; 8] = [8, 24, 64, 100, 256, 512, 1000, 2048];
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+/// One allocation immediately freed.
+fn alloc_dealloc(bencher: &mut Bencher, allocator: &impl GlobalAlloc) {
+    bencher.measure(|| unsafe {
+        let ptr = allocator.alloc(SMALL);
+        allocator.dealloc(ptr, SMALL);
+        ptr
+    });
+}
+
+/// Allocations of mixed sizes all alive at the same time, then freed.
+fn mixed_sizes(bencher: &mut Bencher, allocator: &impl GlobalAlloc) {
+    bencher.set_iterations(10);
+    bencher.measure(|| unsafe {
+        let mut ptrs = [ptr::null_mut(); SIZES.len()];
+        for (ptr, size) in ptrs.iter_mut().zip(SIZES) {
+            *ptr = allocator.alloc(Layout::from_size_align_unchecked(size, 8));
+        }
+        for (ptr, size) in ptrs.iter().zip(SIZES).rev() {
+            allocator.dealloc(*ptr, Layout::from_size_align_unchecked(size, 8));
+        }
+        ptrs[0]
+    });
+}
+
+/// Churn of random sizes: each run frees or allocates a random slot.
+fn churn(bencher: &mut Bencher, allocator: &impl GlobalAlloc) {
+    const SLOTS: usize = 64;
+    let mut rng = Rng::new(0x5EED);
+    let mut slots = [(ptr::null_mut::<u8>(), SMALL); SLOTS];
+
+    bencher.measure(|| unsafe {
+        let slot = &mut slots[(0..=SLOTS - 1).generate(&mut rng)];
+        if slot.0.is_null() {
+            let layout = Layout::from_size_align_unchecked((16..=1024_usize).generate(&mut rng), 8);
+            *slot = (allocator.alloc(layout), layout);
+        } else {
+            allocator.dealloc(slot.0, slot.1);
+            slot.0 = ptr::null_mut();
+        }
+        slot.0
+    });
+
+    for (ptr, layout) in slots {
+        if !ptr.is_null() {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// The growth of a `Vec<u64>` pushed 1000 times, as in the `large_vec` test.
+fn large_vec(bencher: &mut Bencher, allocator: &impl GlobalAlloc) {
+    bencher.set_iterations(10);
+    bencher.measure(|| unsafe {
+        let mut layout = Layout::from_size_align_unchecked(4 * 8, 8);
+        let mut ptr = allocator.alloc(layout);
+        while layout.size() < 1000 * 8 {
+            let new_size = layout.size() * 2;
+            ptr = allocator.realloc(ptr, layout, new_size);
+            layout = Layout::from_size_align_unchecked(new_size, 8);
+        }
+        allocator.dealloc(ptr, layout);
+        ptr
+    });
+}
+
+/// Gives the whole arena to the allocator.
+fn on_arena<A: HeapAllocator>(allocator: A) -> A {
+    unsafe {
+        // SAFETY: the previous allocator using the arena is dropped.
+        allocator.init(ARENA.start(), ARENA.size());
+    }
+    allocator
+}
+
+fn bump() -> Locked<BumpAllocator> {
+    on_arena(Locked::new(BumpAllocator::new()))
+}
+
+fn linked_list<P: Placement>() -> Locked<LinkedListAllocator<P>> {
+    on_arena(Locked::new(LinkedListAllocator::new()))
+}
+
+fn fixed_size_block() -> Locked<FixedSizeBlockAllocator> {
+    on_arena(Locked::new(FixedSizeBlockAllocator::new()))
+}
+
+fn buddy() -> Locked<BuddyAllocator> {
+    on_arena(Locked::new(BuddyAllocator::new()))
+}
+
+/// Compares the placement policies on the same churn.
+fn linked_list_churn<P: Placement>(bencher: &mut Bencher) {
+    let allocator = linked_list::<P>();
+    churn(bencher, &allocator);
+    let stats = allocator.lock().stats();
+    serial_print!(
+        "(average search {}, longest {}, fragmentation {}%) ",
+        stats.average_search_length(),
+        stats.longest_search,
+        stats.fragmentation_percent()
+    );
+}
+
+bench_case! {
+    fn bump_alloc_dealloc(bencher) {
+        alloc_dealloc(bencher, &bump());
+    }
+}
+
+bench_case! {
+    fn bump_mixed_sizes(bencher) {
+        mixed_sizes(bencher, &bump());
+    }
+}
+
+bench_case! {
+    fn bump_large_vec(bencher) {
+        large_vec(bencher, &bump());
+    }
+}
+
+bench_case! {
+    fn linked_list_alloc_dealloc(bencher) {
+        alloc_dealloc(bencher, &linked_list::<FirstFit>());
+    }
+}
+
+bench_case! {
+    fn linked_list_mixed_sizes(bencher) {
+        mixed_sizes(bencher, &linked_list::<FirstFit>());
+    }
+}
+
+bench_case! {
+    fn linked_list_large_vec(bencher) {
+        large_vec(bencher, &linked_list::<FirstFit>());
+    }
+}
+
+bench_case! {
+    fn linked_list_first_fit_churn(bencher) {
+        linked_list_churn::<FirstFit>(bencher);
+    }
+}
+
+bench_case! {
+    fn linked_list_next_fit_churn(bencher) {
+        linked_list_churn::<NextFit>(bencher);
+    }
+}
+
+bench_case! {
+    fn linked_list_best_fit_churn(bencher) {
+        linked_list_churn::<BestFit>(bencher);
+    }
+}
+
+bench_case! {
+    fn fixed_size_block_alloc_dealloc(bencher) {
+        alloc_dealloc(bencher, &fixed_size_block());
+    }
+}
+
+bench_case! {
+    fn fixed_size_block_mixed_sizes(bencher) {
+        mixed_sizes(bencher, &fixed_size_block());
+    }
+}
+
+bench_case! {
+    fn fixed_size_block_large_vec(bencher) {
+        large_vec(bencher, &fixed_size_block());
+    }
+}
+
+bench_case! {
+    fn buddy_alloc_dealloc(bencher) {
+        alloc_dealloc(bencher, &buddy());
+    }
+}
+
+bench_case! {
+    fn buddy_mixed_sizes(bencher) {
+        mixed_sizes(bencher, &buddy());
+    }
+}
+
+bench_case! {
+    fn buddy_large_vec(bencher) {
+        large_vec(bencher, &buddy());
+    }
+}
+
+bench_case! {
+    fn buddy_churn(bencher) {
+        churn(bencher, &buddy());
+    }
+}
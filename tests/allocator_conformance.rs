@@ -0,0 +1,333 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+//! The same suite run against every allocator, each over a private arena.
+
+use core::alloc::Layout;
+use core::panic::PanicInfo;
+use core::ptr;
+
+use os::allocator::{AllocatorStats, HeapAllocator};
+use os::test_utils::test_panic_handler;
+use os::{assert, assert_eq};
+
+const ARENA_SIZE: usize = 32 * 1024;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+fn layout(size: usize, align: usize) -> Layout {
+    Layout::from_size_align(size, align).unwrap()
+}
+
+fn alignment(allocator: &impl HeapAllocator) {
+    for align in (0..=12).map(|shift| 1 << shift) {
+        for size in [1, 7, 64, 1000] {
+            let layout = layout(size, align);
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null(), "{layout:?} failed");
+            assert_eq!(ptr as usize % align, 0, "{layout:?}");
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+}
+
+fn non_overlap(allocator: &impl HeapAllocator, arena: (usize, usize)) {
+    const COUNT: usize = 32;
+    let mut blocks = [(ptr::null_mut::<u8>(), layout(1, 1)); COUNT];
+
+    for (i, block) in (0..).zip(blocks.iter_mut()) {
+        let layout = layout(1 + usize::from(i) * 37 % 300, 1 << (i % 7));
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null(), "{layout:?} failed");
+        assert!(arena.0 <= ptr as usize && ptr as usize + layout.size() <= arena.1);
+        unsafe { ptr.write_bytes(i, layout.size()) };
+        *block = (ptr, layout);
+    }
+
+    for (i, &(ptr, layout)) in (0..).zip(blocks.iter()) {
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+        assert!(bytes.iter().all(|&b| b == i), "block {i} overlaps");
+    }
+
+    for (ptr, layout) in blocks {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+/// Fills the heap, frees everything and checks that the heap can be
+/// filled again with as many blocks.
+fn reuse_after_free(allocator: &impl HeapAllocator) {
+    const MAX_BLOCKS: usize = 1024;
+    let layout = layout(64, 8);
+    let mut blocks = [ptr::null_mut::<u8>(); MAX_BLOCKS];
+
+    let fill = |blocks: &mut [*mut u8; MAX_BLOCKS]| {
+        let mut count = 0;
+        while count < MAX_BLOCKS {
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                break;
+            }
+            blocks[count] = ptr;
+            count += 1;
+        }
+        count
+    };
+
+    let first_count = fill(&mut blocks);
+    assert!(first_count > 0 && first_count < MAX_BLOCKS);
+    for &ptr in &blocks[..first_count] {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    let second_count = fill(&mut blocks);
+    assert_eq!(first_count, second_count);
+    for &ptr in &blocks[..second_count] {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+fn out_of_memory(allocator: &impl HeapAllocator) {
+    for layout in [layout(ARENA_SIZE + 1, 8), layout(ARENA_SIZE * 1024, 4096)] {
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(ptr.is_null(), "{layout:?} should not fit");
+    }
+
+    // The allocator is still usable.
+    let layout = layout(16, 8);
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { allocator.dealloc(ptr, layout) };
+}
+
+/// `GlobalAlloc` does not require supporting zero sized layouts,
+/// all our allocators do anyway.
+fn zero_size(allocator: &impl HeapAllocator) {
+    let mut blocks = [ptr::null_mut::<u8>(); 3];
+    for (block, align) in blocks.iter_mut().zip([1, 8, 64]) {
+        let ptr = unsafe { allocator.alloc(layout(0, align)) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        *block = ptr;
+    }
+
+    let layout_8 = layout(8, 8);
+    let ptr = unsafe { allocator.alloc(layout_8) };
+    assert!(!ptr.is_null());
+    unsafe {
+        ptr.write_bytes(0xFF, 8);
+        allocator.dealloc(ptr, layout_8);
+    }
+
+    for (ptr, align) in blocks.into_iter().zip([1, 8, 64]) {
+        unsafe { allocator.dealloc(ptr, layout(0, align)) };
+    }
+}
+
+fn stats(allocator: &(impl HeapAllocator + AllocatorStats)) {
+    let initial = allocator.heap_stats();
+    assert_eq!(initial.in_use, 0);
+    assert!(initial.heap_size <= ARENA_SIZE && initial.free <= initial.heap_size);
+
+    let layouts = [layout(100, 8), layout(200, 8), layout(300, 8)];
+    let blocks = layouts.map(|layout| unsafe { allocator.alloc(layout) });
+    unsafe { allocator.dealloc(blocks[1], layouts[1]) };
+
+    let stats = allocator.heap_stats();
+    assert_eq!(stats.in_use, 400);
+    assert_eq!(stats.peak_in_use, 600);
+    assert_eq!(stats.allocations, initial.allocations + 3);
+    assert_eq!(stats.frees, initial.frees + 1);
+    assert!(stats.free < initial.free);
+    if let Some(largest_free_block) = stats.largest_free_block {
+        assert!(largest_free_block <= stats.free);
+    }
+
+    for (ptr, layout) in [(blocks[0], layouts[0]), (blocks[2], layouts[2])] {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    assert_eq!(allocator.heap_stats().in_use, 0);
+}
+
+/// The contents are kept when a block grows and shrinks.
+fn realloc(allocator: &impl HeapAllocator) {
+    let layout = layout(64, 8);
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert!(!ptr.is_null());
+    for i in 0..64_u8 {
+        unsafe { ptr.add(usize::from(i)).write(i) };
+    }
+
+    let grown = unsafe { allocator.realloc(ptr, layout, 4000) };
+    assert!(!grown.is_null());
+    assert_eq!(grown as usize % 8, 0);
+    let bytes = unsafe { core::slice::from_raw_parts(grown, 64) };
+    assert!(bytes.iter().zip(0_u8..).all(|(&b, i)| b == i));
+    unsafe { grown.add(64).write_bytes(0xFF, 4000 - 64) };
+
+    let shrunk = unsafe { allocator.realloc(grown, self::layout(4000, 8), 16) };
+    assert!(!shrunk.is_null());
+    let bytes = unsafe { core::slice::from_raw_parts(shrunk, 16) };
+    assert!(bytes.iter().zip(0_u8..).all(|(&b, i)| b == i));
+    unsafe { allocator.dealloc(shrunk, self::layout(16, 8)) };
+}
+
+/// `allocator` manages the first half of the arena, once full it serves
+/// allocations from the second half given by `extend`.
+fn extend(allocator: &impl HeapAllocator, arena: (usize, usize)) {
+    const MAX_BLOCKS: usize = ARENA_SIZE / 1024;
+    let layout = layout(1024, 8);
+    let mut blocks = [ptr::null_mut::<u8>(); MAX_BLOCKS];
+    let mut count = 0;
+    let mut fill = |blocks: &mut [*mut u8; MAX_BLOCKS]| {
+        while count < MAX_BLOCKS {
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                break;
+            }
+            blocks[count] = ptr;
+            count += 1;
+        }
+        count
+    };
+
+    let half_count = fill(&mut blocks);
+    assert!(half_count <= MAX_BLOCKS / 2);
+    unsafe {
+        // SAFETY: the second half of the arena is unused.
+        allocator.extend(ARENA_SIZE / 2);
+    }
+    let full_count = fill(&mut blocks);
+    assert!(
+        full_count >= half_count + MAX_BLOCKS / 4,
+        "the extension is not used"
+    );
+    assert!(blocks[..full_count]
+        .iter()
+        .all(|&ptr| arena.0 <= ptr as usize && ptr as usize + layout.size() <= arena.1));
+
+    for &ptr in &blocks[..full_count] {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+/// Generates the suite for one allocator, with its own arena.
+macro_rules! conformance_tests {
+    ($module:ident, $new_allocator:expr) => {
+        mod $module {
+            use os::allocator::{AllocatorStats, HeapAllocator};
+            use os::test_utils::Arena;
+
+            static ARENA: Arena<{ super::ARENA_SIZE }> = Arena::new();
+
+            /// A fresh allocator over the whole arena.
+            fn allocator() -> impl HeapAllocator + AllocatorStats {
+                let allocator = $new_allocator;
+                unsafe {
+                    // SAFETY: the tests run one after the other and the
+                    // previous allocator of the arena is dropped.
+                    allocator.init(ARENA.start(), ARENA.size());
+                }
+                allocator
+            }
+
+            /// A fresh allocator over the first half of the arena.
+            fn half_allocator() -> impl HeapAllocator + AllocatorStats {
+                let allocator = $new_allocator;
+                unsafe {
+                    // SAFETY: see `allocator`.
+                    allocator.init(ARENA.start(), ARENA.size() / 2);
+                }
+                allocator
+            }
+
+            #[test_case]
+            fn alignment() {
+                super::alignment(&allocator());
+            }
+
+            #[test_case]
+            fn non_overlap() {
+                super::non_overlap(&allocator(), (ARENA.start(), ARENA.end()));
+            }
+
+            #[test_case]
+            fn reuse_after_free() {
+                super::reuse_after_free(&allocator());
+            }
+
+            #[test_case]
+            fn out_of_memory() {
+                super::out_of_memory(&allocator());
+            }
+
+            #[test_case]
+            fn zero_size() {
+                super::zero_size(&allocator());
+            }
+
+            #[test_case]
+            fn stats() {
+                super::stats(&allocator());
+            }
+
+            #[test_case]
+            fn realloc() {
+                super::realloc(&allocator());
+            }
+
+            #[test_case]
+            fn extend() {
+                super::extend(&half_allocator(), (ARENA.start(), ARENA.end()));
+            }
+        }
+    };
+}
+
+conformance_tests!(
+    bump,
+    os::allocator::Locked::new(os::allocator::bump::BumpAllocator::new())
+);
+conformance_tests!(
+    linked_list,
+    os::allocator::Locked::new(os::allocator::linked_list::LinkedListAllocator::<
+        os::allocator::linked_list::FirstFit,
+    >::new())
+);
+conformance_tests!(
+    linked_list_next_fit,
+    os::allocator::Locked::new(os::allocator::linked_list::LinkedListAllocator::<
+        os::allocator::linked_list::NextFit,
+    >::new())
+);
+conformance_tests!(
+    linked_list_best_fit,
+    os::allocator::Locked::new(os::allocator::linked_list::LinkedListAllocator::<
+        os::allocator::linked_list::BestFit,
+    >::new())
+);
+conformance_tests!(
+    fixed_size_block,
+    os::allocator::Locked::new(os::allocator::fixed_size_block::FixedSizeBlockAllocator::new())
+);
+conformance_tests!(
+    buddy,
+    os::allocator::Locked::new(os::allocator::buddy::BuddyAllocator::new())
+);
+conformance_tests!(
+    external,
+    os::allocator::Locked::new(os::allocator::external::ExternalHeap::new())
+);
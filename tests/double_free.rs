@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+//! Frees a block twice through a `DebugAlloc`, the second free must panic.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bootloader::{entry_point, BootInfo};
+
+use os::allocator::debug::DebugAlloc;
+use os::allocator::linked_list::{FirstFit, LinkedListAllocator};
+use os::allocator::{HeapAllocator, Locked};
+use os::test_utils::Arena;
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static ARENA: Arena<4096> = Arena::new();
+static SECOND_FREE: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("double_free::double_free...\t");
+
+    let allocator = DebugAlloc::new(Locked::new(LinkedListAllocator::<FirstFit>::new()));
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        // SAFETY: the arena is only used here.
+        allocator.init(ARENA.start(), ARENA.size());
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, layout);
+        SECOND_FREE.store(true, Ordering::SeqCst);
+        allocator.dealloc(ptr, layout);
+    }
+
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if SECOND_FREE.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
@@ -10,8 +10,9 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use x86_64::VirtAddr;
 
-use os::allocator::HEAP_SIZE;
+use os::allocator::{HEAP_SIZE, HEAP_START};
 use os::test_utils::test_panic_handler;
 
 entry_point!(main);
@@ -63,3 +64,27 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 42);
 }
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let buffer = alloc::vec![1_u8; HEAP_SIZE * 4];
+    assert!(buffer.iter().all(|&byte| byte == 1));
+    assert!(os::allocator::heap_stats().heap_size > HEAP_SIZE * 4);
+}
+
+#[test_case]
+fn large_growth_uses_huge_pages() {
+    const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    let size = os::allocator::heap_stats().free + 2 * HUGE_PAGE_SIZE;
+    let buffer = alloc::vec![1_u8; size];
+    let heap_size = os::allocator::heap_stats().heap_size;
+    assert_eq!((HEAP_START + heap_size) % HUGE_PAGE_SIZE, 0);
+
+    let last_page = VirtAddr::new((HEAP_START + heap_size - HUGE_PAGE_SIZE) as u64);
+    let memory = os::memory::MEMORY_MANAGER.lock();
+    let page_size = memory.as_ref().unwrap().page_size(last_page);
+    drop(memory);
+    assert_eq!(page_size, Some(HUGE_PAGE_SIZE as u64));
+    assert!(buffer.iter().all(|&byte| byte == 1));
+}
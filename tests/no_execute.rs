@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+//! Jumps into the heap, the instruction fetch must fault.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::mem;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static CODE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("no_execute::jump_into_heap...\t");
+
+    // A `ret` instruction.
+    let code = Box::new([0xC3_u8; 16]);
+    CODE.store(code.as_ptr() as u64, Ordering::SeqCst);
+
+    // The test IDT only handles the page fault.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let function = unsafe {
+        // SAFETY: the call must fault, the code only returns otherwise.
+        mem::transmute::<*const u8, extern "C" fn()>(code.as_ptr())
+    };
+    function();
+
+    serial_println!("[jump into the heap not detected]");
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if Cr2::read().as_u64() == CODE.load(Ordering::SeqCst) && error_code.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{:?} at {:?}", error_code, Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_utils::test_panic_handler(info)
+}
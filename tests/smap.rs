@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+//! Reads a user page outside of `copy_from_user`, SMAP must make the read
+//! fault.
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
+
+use os::memory::{copy_from_user, copy_to_user};
+use os::test_utils::map_user_pages;
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+static DATA: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("smap::read_user_page...\t");
+
+    if !os::cpu::features().smap {
+        serial_println!("[SMAP not supported]");
+        exit_qemu(QemuExitCode::Failed);
+        os::hlt_loop();
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let data = map_user_pages(4096, flags);
+    DATA.store(data.as_u64(), Ordering::SeqCst);
+
+    // The helpers are allowed to access the page.
+    copy_to_user(data, &42_u64.to_ne_bytes()).unwrap();
+    let mut read = [0; 8];
+    copy_from_user(&mut read, data).unwrap();
+    if u64::from_ne_bytes(read) != 42 {
+        serial_println!("[failed]\ncopied {:?}", read);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    // The test IDT only handles the page fault.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let value = unsafe {
+        // SAFETY: the read must fault, the page is mapped otherwise.
+        data.as_ptr::<u64>().read_volatile()
+    };
+
+    serial_println!("[read of a user page not detected: {}]", value);
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let read = !error_code
+        .intersects(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::INSTRUCTION_FETCH);
+    if Cr2::read().as_u64() == DATA.load(Ordering::SeqCst)
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && read
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{:?} at {:?}", error_code, Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_utils::test_panic_handler(info)
+}
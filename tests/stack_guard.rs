@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+//! Overflows the boot stack, the double fault handler must report the hit of
+//! its guard page.
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::registers::control::Cr2;
+
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("stack_guard::stack_guard...\t");
+
+    stack_overflow();
+
+    serial_println!("[execution continued after stack overflow]");
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // To prevent tail recursion optimizations.
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if os::memory::overflowed_stack(Cr2::read()) == Some("boot stack") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
@@ -4,6 +4,7 @@
 
 use core::panic::PanicInfo;
 
+use bootloader::{entry_point, BootInfo};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -21,11 +22,14 @@ lazy_static! {
     };
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
-    os::gdt::init();
+    os::init(boot_info);
+    // The test IDT only handles the double fault.
+    x86_64::instructions::interrupts::disable();
     init_test_idt();
 
     stack_overflow();
@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+//! Runs `sgdt` in ring 3, UMIP must make it raise a general protection fault.
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
+
+use os::memory::copy_to_user;
+use os::test_utils::map_user_pages;
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+/// `sgdt [rsp]` then `ud2`.
+const USER_CODE: [u8; 6] = [0x0F, 0x01, 0x04, 0x24, 0x0F, 0x0B];
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.general_protection_fault
+            .set_handler_fn(test_general_protection_fault_handler);
+        idt.invalid_opcode
+            .set_handler_fn(test_invalid_opcode_handler);
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("umip::sgdt_in_ring_3...\t");
+
+    if !os::cpu::features().umip {
+        serial_println!("[UMIP not supported]");
+        exit_qemu(QemuExitCode::Failed);
+        os::hlt_loop();
+    }
+
+    let code = map_user_pages(4096, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    copy_to_user(code, &USER_CODE).unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let stack = map_user_pages(4096, flags);
+    // Room for the 10 bytes written by `sgdt`.
+    let stack_top = stack + 4096_u64 - 16_u64;
+
+    // The test IDT only handles the faults of the user code.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let (code_selector, data_selector) = os::gdt::user_selectors();
+    unsafe {
+        // SAFETY: the code and its stack are mapped in user pages, with
+        // interrupts disabled.
+        asm!(
+            "push {data}",
+            "push {stack}",
+            "push {flags}",
+            "push {code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) u64::from(data_selector.0),
+            stack = in(reg) stack_top.as_u64(),
+            // Only the reserved bit, interrupts stay disabled.
+            flags = in(reg) 0x2_u64,
+            code = in(reg) u64::from(code_selector.0),
+            entry = in(reg) code.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+extern "x86-interrupt" fn test_general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) {
+    if stack_frame.code_segment & 3 == 3 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{:#?}", stack_frame);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_invalid_opcode_handler(_stack_frame: InterruptStackFrame) {
+    serial_println!("[sgdt in ring 3 not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    serial_println!("[failed]\n{:?}\n{:#?}", error_code, stack_frame);
+    exit_qemu(QemuExitCode::Failed);
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+//! Writes to the kernel text, the write must fault.
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    os::init(boot_info);
+
+    serial_print!("write_protect::write_to_text...\t");
+
+    // The test IDT only handles the page fault.
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    unsafe {
+        // SAFETY: the write must fault, it changes nothing.
+        text().as_mut_ptr::<u8>().write_volatile(0xC3);
+    }
+
+    serial_println!("[write to the text not detected]");
+    exit_qemu(QemuExitCode::Failed);
+
+    os::hlt_loop();
+}
+
+fn text() -> VirtAddr {
+    let function: fn() -> VirtAddr = text;
+    VirtAddr::new(function as usize as u64)
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if Cr2::read() == text() && error_code.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n{:?} at {:?}", error_code, Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_utils::test_panic_handler(info)
+}